@@ -25,6 +25,7 @@ import os
 import contextlib
 import socket
 import sys
+import zlib
 
 import numpy as np
 import cbor2 as cbor
@@ -214,6 +215,17 @@ class RunModelReply:
         self.__dict__.update(entries)
 
 
+def _decompress(data: bytes, compression: Optional[str]) -> bytes:
+    # the server compresses large outputs when configured to
+    if compression is None:
+        return data
+    if compression == "gzip":
+        return zlib.decompress(data, 16 + zlib.MAX_WBITS)
+    if compression == "deflate":
+        return zlib.decompress(data, -zlib.MAX_WBITS)
+    raise ValueError(f"Unsupported output compression {compression}")
+
+
 @dataclass
 class UploadResponse:
     model_id: str
@@ -744,10 +756,14 @@ class BlindAiConnection(contextlib.AbstractContextManager):
         r = self._conn.post(f"{self._attested_url}/run", data=bytes_run_data)
         r.raise_for_status()
         run_model_reply = RunModelReply(**cbor.loads(r.content))
+        compression = getattr(run_model_reply, "compression", None)
 
         ret = RunModelResponse(
             output=[
-                Tensor(TensorInfo(**output["info"]), output["bytes_data"])
+                Tensor(
+                    TensorInfo(**output["info"]),
+                    _decompress(output["bytes_data"], compression),
+                )
                 for output in run_model_reply.outputs
             ]
         )
@@ -0,0 +1,92 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Marks a model as degraded after `threshold` consecutive inference
+/// failures. A degraded model fails fast until it is reset or `cooldown`
+/// has passed, it is then tried again: one more failure degrades it again,
+/// a success closes the breaker.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    consecutive_failures: AtomicU32,
+    degraded_since: Mutex<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            consecutive_failures: AtomicU32::new(0),
+            degraded_since: Mutex::new(None),
+        }
+    }
+
+    /// Fails if the model is degraded and still cooling down.
+    pub fn check(&self) -> Result<()> {
+        if let Some(since) = *self.degraded_since.lock().unwrap() {
+            if since.elapsed() < self.cooldown {
+                bail!(
+                    "Degraded: the model failed {} inferences in a row, retry later",
+                    self.consecutive_failures.load(Ordering::Relaxed)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of an inference, returns the number of
+    /// consecutive failures if this one degraded the model.
+    pub fn record(&self, success: bool) -> Option<u32> {
+        if success {
+            self.reset();
+            return None;
+        }
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.threshold {
+            return None;
+        }
+        *self.degraded_since.lock().unwrap() = Some(Instant::now());
+        Some(failures)
+    }
+
+    pub fn reset(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        *self.degraded_since.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retried_after_cooldown() {
+        let breaker = CircuitBreaker::new(2, Duration::ZERO);
+        assert_eq!(breaker.record(false), None);
+        assert_eq!(breaker.record(false), Some(2));
+        // no cooldown: the model can be tried again right away
+        assert!(breaker.check().is_ok());
+        // still failing, degraded again
+        assert_eq!(breaker.record(false), Some(3));
+        assert_eq!(breaker.record(true), None);
+        assert!(breaker.degraded_since.lock().unwrap().is_none());
+    }
+}
@@ -12,17 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::model::ModelDatumType;
-use crate::model_store::ModelStore;
+use crate::coalescer::Coalescer;
+use crate::compression::{Compression, OutputCompression};
+use crate::health::HealthCheck;
+use crate::idempotency::{IdempotencyCache, KeyReused};
+use crate::input_cache::InputCache;
+use crate::model::{
+    DuplicateOutputNames, Endianness, GraphVariant, InferenceModel, InferenceOptions,
+    ModelDatumType, ModelOptions, TensorLayout, TopK, ValueRange,
+};
+use crate::model_store::{
+    parse_env, BuildInfo, ModelDescription, ModelMetadata, ModelStore, ModelStoreError, StoreStats,
+    StoreStatsDelta, TRACT_VERSION,
+};
 use crate::telemetry::{self, TelemetryEventProps};
+use crate::watchdog::InferenceWatchdog;
 use anyhow::{Error, Result};
-use log::{error, info};
+use log::{error, info, LevelFilter};
+use ring::digest;
 use serde_derive::{Deserialize, Serialize};
+use std::borrow::Borrow;
 use std::io::Read;
 use std::mem::size_of;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -30,6 +44,8 @@ pub struct TensorInfo {
     pub fact: Vec<usize>,
     pub datum_type: ModelDatumType,
     pub node_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<TensorLayout>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -39,11 +55,41 @@ pub struct SerializedTensor {
     pub bytes_data: Vec<u8>,
 }
 
+type InferenceResult = Arc<Result<(Vec<SerializedTensor>, GraphVariant)>>;
+// model, digest of the inputs and settings of an inference
+type InferenceKey = (Uuid, Vec<u8>, InferenceOptions);
+type InferenceReply = Arc<(Vec<SerializedTensor>, GraphVariant)>;
+// what a replay must match to get the original result back
+type IdempotentRequest = (InferenceKey, Endianness);
+
+// how long and how many results are kept for the replays of idempotent requests
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(600);
+const IDEMPOTENCY_CAPACITY: usize = 10_000;
+// longest warm-up corpus an upload may carry
+const MAX_WARMUP_CORPUS: usize = 16;
+// how often the canary model is run
+const HEALTH_CHECK_PERIOD: Duration = Duration::from_secs(30);
+
 #[derive(Clone)]
 pub(crate) struct Exchanger {
     model_store: Arc<ModelStore>,
     max_model_size: usize,
     max_input_size: usize,
+    watchdog: Arc<InferenceWatchdog>,
+    // time the inferences running on a deleted model get to complete
+    drain_timeout: Duration,
+    // concurrent identical inferences on deterministic models are only run once
+    coalescer: Arc<Coalescer<InferenceKey, InferenceResult>>,
+    // results of the requests sent with an idempotency key, before their
+    // conversion to the byte order of the client
+    idempotency_cache: Arc<IdempotencyCache<(Uuid, String), (IdempotentRequest, InferenceReply)>>,
+    // canary model proving that inferences work, if one is configured
+    health_check: Option<Arc<HealthCheck>>,
+    output_compression: Option<OutputCompression>,
+    // inputs registered once and referenced by the requests
+    input_cache: Option<Arc<InputCache>>,
+    // statistics returned by the previous /stats request
+    previous_stats: Arc<Mutex<StoreStats>>,
 }
 
 #[derive(Deserialize)]
@@ -51,12 +97,74 @@ struct DeleteModel {
     model_id: String,
 }
 
+#[derive(Deserialize)]
+struct DeleteModels {
+    model_ids: Vec<String>,
+}
+
+/// Whether each of the requested models was deleted, in the request order.
+#[derive(Serialize)]
+pub(crate) struct DeleteModelsReply {
+    deleted: Vec<bool>,
+}
+
+#[derive(Deserialize)]
+struct CloneModel {
+    model_id: String,
+    model_name: String,
+}
+
+#[derive(Deserialize)]
+struct ResetCircuitBreaker {
+    model_id: String,
+}
+
+#[derive(Deserialize)]
+struct SetModelLogLevel {
+    model_id: String,
+    // no level removes the override
+    log_level: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct RunModel {
     model_id: String,
     model_hash: String,
     pub inputs: Vec<SerializedTensor>,
     client_info: ClientInfo,
+    #[serde(default)]
+    schema_version: Option<u32>,
+    #[serde(default)]
+    output_datum_type: Option<ModelDatumType>,
+    #[serde(default)]
+    allow_lossy_cast: bool,
+    #[serde(default)]
+    top_k: Option<TopK>,
+    // byte order of the inputs, the outputs are sent back in the same one
+    #[serde(default)]
+    endianness: Endianness,
+    // replays of a request with the same key get the original result back
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    // bound to the seed input of stochastic models
+    #[serde(default)]
+    seed: Option<u64>,
+    // handles of inputs registered earlier, added after `inputs`
+    #[serde(default)]
+    cached_inputs: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterInput {
+    model_id: String,
+    input: SerializedTensor,
+    #[serde(default)]
+    endianness: Endianness,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RegisterInputReply {
+    handle: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -67,18 +175,123 @@ struct UploadModel {
     model_name: String,
     optimize: bool,
     client_info: ClientInfo,
+    #[serde(default)]
+    schema_version: u32,
+    #[serde(default)]
+    deterministic: bool,
+    #[serde(default)]
+    log_level: Option<String>,
+    #[serde(default)]
+    ab_unoptimized_fraction: Option<f64>,
+    #[serde(default)]
+    input_layout: Option<TensorLayout>,
+    #[serde(default)]
+    duplicate_output_names: DuplicateOutputNames,
+    #[serde(default)]
+    input_range: Option<ValueRange>,
+    // detached signature of the model, checked when the store requires one
+    #[serde(default, with = "serde_bytes")]
+    signature: Option<Vec<u8>>,
+    // run the model before replying, on the corpus if there is one or on
+    // zero-filled inputs otherwise
+    #[serde(default)]
+    warmup: bool,
+    #[serde(default)]
+    warmup_corpus: Vec<Vec<SerializedTensor>>,
+}
+
+impl UploadModel {
+    fn model_options(&self) -> Result<ModelOptions> {
+        Ok(ModelOptions {
+            optimize: self.optimize,
+            schema_version: self.schema_version,
+            deterministic: self.deterministic,
+            log_level: parse_log_level(self.log_level.as_deref())?,
+            ab_unoptimized_fraction: self.ab_unoptimized_fraction,
+            input_layout: self.input_layout,
+            duplicate_output_names: self.duplicate_output_names,
+            input_range: self.input_range,
+            ..Default::default()
+        })
+    }
+}
+
+// an upload for the bytes of an existing model, whose name is kept
+#[derive(Debug, Deserialize)]
+struct ReplaceModel {
+    model_id: String,
+    #[serde(flatten)]
+    upload: UploadModel,
 }
 
 #[derive(Serialize)]
 pub(crate) struct SendModelReply {
+    // hash of the bytes the store loaded: when it canonicalizes the models,
+    // that of the canonical form rather than of the upload, whose bytes are
+    // not kept
     #[serde(with = "serde_bytes")]
     hash: Vec<u8>,
     model_id: String,
 }
 
+// fails the reads past `remaining` bytes, where `take` would cut the model
+struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.remaining = self.remaining.checked_sub(read).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Model is too big")
+        })?;
+        Ok(read)
+    }
+}
+
 #[derive(Default, Serialize)]
 pub(crate) struct RunModelReply {
     outputs: Vec<SerializedTensor>,
+    // graph that served the inference
+    variant: GraphVariant,
+    build: BuildInfo,
+    // algorithm the bytes of every output are compressed with, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HealthReply {
+    pub ready: bool,
+}
+
+/// Counters of the store, and how they moved since the previous request.
+#[derive(Serialize)]
+pub(crate) struct StatsReply {
+    #[serde(flatten)]
+    stats: StoreStats,
+    dedup_ratio: f64,
+    since_previous: StoreStatsDelta,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ModelsMerkleRootReply {
+    #[serde(with = "serde_bytes")]
+    root: Vec<u8>,
+}
+
+/// What the server can run, for clients to validate their models before
+/// uploading them.
+#[derive(Serialize)]
+pub(crate) struct EnclaveCapabilities {
+    datum_types: Vec<ModelDatumType>,
+    tract_version: &'static str,
+    // range of ONNX operator sets tract is tested against
+    onnx_opset_min: i64,
+    onnx_opset_max: i64,
+    max_model_size: usize,
+    max_input_size: usize,
 }
 
 /// This model represents the ClientInfo used for telemetry
@@ -95,11 +308,90 @@ pub(crate) struct ClientInfo {
 }
 
 impl Exchanger {
-    pub fn new(model_store: Arc<ModelStore>, max_model_size: usize, max_input_size: usize) -> Self {
+    pub fn new(
+        model_store: Arc<ModelStore>,
+        max_model_size: usize,
+        max_input_size: usize,
+        max_inference_time: Duration,
+    ) -> Self {
+        let watchdog = Arc::new(InferenceWatchdog::new(max_inference_time));
+        watchdog.spawn(Duration::from_secs(1), Arc::clone(&model_store));
+        model_store.spawn_integrity_check();
+        model_store.spawn_expiry_sweeper();
+        let health_check = HealthCheck::from_env().map(Arc::new);
+        if let Some(health_check) = &health_check {
+            health_check.spawn(HEALTH_CHECK_PERIOD);
+        }
+        let idempotency_cache =
+            Arc::new(IdempotencyCache::new(IDEMPOTENCY_TTL, IDEMPOTENCY_CAPACITY));
+        let input_cache = InputCache::from_env().map(Arc::new);
+        {
+            // the results and the inputs of a model are not kept once it is gone
+            let idempotency_cache = Arc::clone(&idempotency_cache);
+            let input_cache = input_cache.clone();
+            model_store.on_model_removed(move |model_id| {
+                idempotency_cache.retain(|(key_model_id, _)| *key_model_id != model_id);
+                if let Some(input_cache) = &input_cache {
+                    input_cache.forget_model(model_id);
+                }
+            });
+        }
         Self {
-            model_store,
             max_model_size,
             max_input_size,
+            watchdog,
+            drain_timeout: parse_env("BLINDAI_DRAIN_TIMEOUT_SECS")
+                .map_or(max_inference_time, Duration::from_secs),
+            coalescer: Arc::new(Coalescer::new()),
+            idempotency_cache,
+            health_check,
+            output_compression: OutputCompression::from_env(),
+            input_cache,
+            previous_stats: Arc::new(Mutex::new(model_store.stats())),
+            model_store,
+        }
+    }
+
+    pub fn capabilities(&self) -> EnclaveCapabilities {
+        EnclaveCapabilities {
+            datum_types: ModelDatumType::all(),
+            tract_version: TRACT_VERSION,
+            onnx_opset_min: 9,
+            onnx_opset_max: 13,
+            max_model_size: self.max_model_size,
+            max_input_size: self.max_input_size,
+        }
+    }
+
+    /// Without a canary model, the server is ready as soon as it is up.
+    pub fn health(&self) -> HealthReply {
+        HealthReply {
+            ready: self
+                .health_check
+                .as_ref()
+                .map_or(true, |health_check| health_check.is_ready()),
+        }
+    }
+
+    pub fn stats(&self) -> StatsReply {
+        let mut previous_stats = self.previous_stats.lock().unwrap();
+        let stats = self.model_store.stats();
+        let since_previous = stats.delta(&previous_stats);
+        *previous_stats = stats;
+        StatsReply {
+            stats,
+            dedup_ratio: stats.dedup_ratio(),
+            since_previous,
+        }
+    }
+
+    pub fn list_models(&self) -> Vec<ModelDescription> {
+        self.model_store.list_models()
+    }
+
+    pub fn models_merkle_root(&self) -> ModelsMerkleRootReply {
+        ModelsMerkleRootReply {
+            root: self.model_store.models_merkle_root(),
         }
     }
 
@@ -115,6 +407,7 @@ impl Exchanger {
                 .read_to_end(&mut data)?;
             serde_cbor::from_slice(&data)?
         };
+        let options = upload_model_body.model_options()?;
 
         let max_model_size = self.max_model_size;
         let mut model_size = 0usize;
@@ -124,7 +417,7 @@ impl Exchanger {
         if model_size == 0 {
             model_size = upload_model_body.length.try_into()?;
             model_name = if !upload_model_body.model_name.is_empty() {
-                Some(upload_model_body.model_name)
+                Some(upload_model_body.model_name.clone())
             } else {
                 None
             };
@@ -137,10 +430,15 @@ impl Exchanger {
             return Err(Error::msg("Received no data".to_string()));
         }
 
-        let (model_id, model_hash) = self.model_store.add_model(
+        self.check_warmup_corpus(&upload_model_body.warmup_corpus)?;
+
+        // the model is warmed up before it is registered, like any inference
+        let (model_id, model_hash) = self.model_store.add_prepared_model(
             &upload_model_body.model,
+            upload_model_body.signature.as_deref(),
             model_name.clone(),
-            upload_model_body.optimize,
+            options,
+            |model| self.warm_up(model, &upload_model_body),
         )?;
 
         // End the timer for the telemetry event
@@ -164,6 +462,98 @@ impl Exchanger {
         })
     }
 
+    /// Loads the model sent as the raw request body without buffering it,
+    /// its name and `optimize=true` are given in the query string. Signed or
+    /// warmed up uploads, and the other options, go through `send_model`.
+    pub fn stream_model(&self, request: &rouille::Request) -> Result<SendModelReply> {
+        let model_name = request
+            .get_param("model_name")
+            .filter(|model_name| !model_name.is_empty());
+        let options = ModelOptions {
+            optimize: request.get_param("optimize").as_deref() == Some("true"),
+            ..Default::default()
+        };
+        let model_data = LimitedReader {
+            inner: request.data().expect("Could not get input"),
+            remaining: self.max_model_size,
+        };
+        let (model_id, model_hash) = self
+            .model_store
+            .add_model_from_reader(model_data, model_name, options)?;
+        Ok(SendModelReply {
+            hash: model_hash.as_ref().to_vec(),
+            model_id: model_id.to_string(),
+        })
+    }
+
+    fn check_warmup_corpus(&self, corpus: &[Vec<SerializedTensor>]) -> Result<()> {
+        if corpus.len() > MAX_WARMUP_CORPUS {
+            return Err(Error::msg("Warm-up corpus too long".to_string()));
+        }
+        let too_big = corpus.iter().any(|inputs| {
+            inputs
+                .iter()
+                .map(|input| input.bytes_data.len())
+                .sum::<usize>()
+                > self.max_input_size
+        });
+        if too_big {
+            return Err(Error::msg("Input too big".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Runs an uploaded model before it is registered, if the upload asks
+    /// for it.
+    fn warm_up(&self, model: &InferenceModel, upload_model_body: &UploadModel) -> Result<()> {
+        let corpus = &upload_model_body.warmup_corpus;
+        if !upload_model_body.warmup && corpus.is_empty() {
+            return Ok(());
+        }
+        let _reader_permit = self.model_store.admit_reader()?;
+        let inference_guard = self.watchdog.start(model.model_id());
+        if let Err(err) = model.warm_up(corpus) {
+            error!("Warm-up of model {} failed: {}", model.model_id(), err);
+            return Err(Error::msg(format!("Warm-up failed: {}", err)));
+        }
+        if inference_guard.timed_out() {
+            return Err(Error::msg("Warm-up timed out".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Swaps the bytes of a model for those of an upload, keeping its id
+    /// and name. The results and the inputs kept for the old bytes are
+    /// dropped.
+    pub fn replace_model(&self, request: &rouille::Request) -> Result<SendModelReply> {
+        let mut data_stream = request.data().expect("Could not get the input");
+        let mut data: Vec<u8> = vec![];
+        data_stream.read_to_end(&mut data)?;
+
+        let body: ReplaceModel = serde_cbor::from_slice(&data)?;
+        let model_id = Uuid::from_str(&body.model_id)?;
+        let upload_model_body = &body.upload;
+        if upload_model_body.model.len() > self.max_model_size {
+            return Err(Error::msg("Model is too big".to_string()));
+        }
+        if upload_model_body.model.is_empty() {
+            return Err(Error::msg("Received no data".to_string()));
+        }
+        self.check_warmup_corpus(&upload_model_body.warmup_corpus)?;
+
+        let model_hash = self.model_store.replace_prepared_model(
+            model_id,
+            &upload_model_body.model,
+            upload_model_body.signature.as_deref(),
+            upload_model_body.model_options()?,
+            |model| self.warm_up(model, upload_model_body),
+        )?;
+        Ok(SendModelReply {
+            hash: model_hash.as_ref().to_vec(),
+            model_id: model_id.to_string(),
+        })
+    }
+
     pub fn run_model(&self, request: &rouille::Request) -> Result<RunModelReply, Error> {
         let max_input_size = self.max_input_size;
 
@@ -171,7 +561,7 @@ impl Exchanger {
         let mut data: Vec<u8> = vec![];
         data_stream.read_to_end(&mut data)?;
 
-        let run_model_body: RunModel = serde_cbor::from_slice(&data)?;
+        let mut run_model_body: RunModel = serde_cbor::from_slice(&data)?;
 
         // Start the timer for the telemetry event
         let start_time = Instant::now();
@@ -217,34 +607,106 @@ impl Exchanger {
             }
         };
 
-        let res = self.model_store.use_model(uuid, |model| {
+        let endianness = run_model_body.endianness;
+        for input in &mut run_model_body.inputs {
+            endianness.convert(input);
+        }
+        let cached = match &self.input_cache {
+            _ if run_model_body.cached_inputs.is_empty() => vec![],
+            Some(input_cache) => input_cache.resolve(&run_model_body.cached_inputs, uuid)?,
+            None => return Err(Error::msg("Inputs are not cached on this server")),
+        };
+        // the cached inputs are shared with the cache, not copied
+        let inputs: Vec<&SerializedTensor> = run_model_body
+            .inputs
+            .iter()
+            .chain(cached.iter().map(|input| &**input))
+            .collect();
+
+        let options = InferenceOptions {
+            output_datum_type: run_model_body.output_datum_type,
+            allow_lossy_cast: run_model_body.allow_lossy_cast,
+            top_k: run_model_body.top_k.take(),
+            seed: run_model_body.seed,
+        };
+        let idempotency_key = run_model_body.idempotency_key.take().map(|key| (uuid, key));
+        let idempotent_request = match idempotency_key {
+            Some(_) => Some(((uuid, inputs_digest(&inputs)?, options.clone()), endianness)),
+            None => None,
+        };
+        let reader_permit = self.model_store.admit_reader()?;
+        let inference_guard = self.watchdog.start(uuid);
+        let res = self.model_store.try_use_model(uuid, |model| {
+            model.check_available()?;
+            // replays are only served by the model that computed the result,
+            // and only to the request that got it first
+            if let Some((request, reply)) = idempotency_key
+                .as_ref()
+                .and_then(|key| self.idempotency_cache.get(key))
+            {
+                if Some(&request) != idempotent_request.as_ref() {
+                    return Err(KeyReused.into());
+                }
+                let (outputs, variant) = &*reply;
+                return Ok((Ok((outputs.clone(), *variant)), true));
+            }
+            model.check_schema_version(run_model_body.schema_version)?;
+            model.check_input_layouts(&inputs)?;
+            model.check_input_values(&inputs)?;
+            model.check_output_cast(&options)?;
+            model.check_top_k(&options)?;
+            model.check_seed(&options)?;
             // uncomment to run benches
             // bench(3, 50, || {
             //     model.run_inference(&mut run_model_body.inputs.clone()[..]);
             // });
-            (
-                model.run_inference(run_model_body.inputs.as_slice()),
-                model.model_name().map(|s| s.to_string()),
-            )
+            let inputs = inputs.as_slice();
+            let result = if model.is_deterministic() {
+                let key = (uuid, inputs_digest(inputs)?, options.clone());
+                match &*self
+                    .coalescer
+                    .run(key, || Arc::new(model.run_inference(inputs, &options)))
+                {
+                    Ok(result) => Ok(result.clone()),
+                    Err(err) => Err(Error::msg(err.to_string())),
+                }
+            } else {
+                model.run_inference(inputs, &options)
+            };
+            Ok::<_, Error>((result, false))
         });
 
         let res = match res {
-            Some(res) => res,
-            None => {
-                error!("Error in model match");
-                return Err(Error::msg("Model doesn't exist".to_string()));
+            Ok(res) => res?,
+            Err(err) => {
+                error!("Error in model match: {}", err);
+                return Err(err.into());
             }
         };
 
-        let (result, _model_name) = res;
+        let (result, replayed) = res;
+
+        if inference_guard.timed_out() {
+            error!("Inference on model {} timed out", uuid);
+            return Err(Error::msg("Inference timed out".to_string()));
+        }
+        drop(inference_guard);
+        drop(reader_permit);
 
-        let outputs = match result {
+        let (mut outputs, variant) = match result {
             Ok(res) => res,
             Err(err) => {
                 error!("Error while running inference: {}", err);
                 return Err(Error::msg("Unknown error".to_string()));
             }
         };
+        if let (Some(key), Some(request), false) = (idempotency_key, idempotent_request, replayed) {
+            self.idempotency_cache
+                .insert(key, (request, Arc::new((outputs.clone(), variant))));
+        }
+        for output in &mut outputs {
+            endianness.convert(output);
+        }
 
         // End the timer for the telemetry event
         let elapsed = start_time.elapsed();
@@ -259,7 +721,68 @@ impl Exchanger {
             None,
         );
 
-        Ok(RunModelReply { outputs })
+        self.run_model_reply(outputs, variant)
+    }
+
+    fn run_model_reply(
+        &self,
+        mut outputs: Vec<SerializedTensor>,
+        variant: GraphVariant,
+    ) -> Result<RunModelReply> {
+        let compression = match &self.output_compression {
+            Some(output_compression) => output_compression.compress(&mut outputs)?,
+            None => None,
+        };
+        Ok(RunModelReply {
+            outputs,
+            variant,
+            build: self.model_store.build_info(),
+            compression,
+        })
+    }
+
+    /// Loads an upload the way `send_model` would, without registering it,
+    /// and describes its inputs and outputs.
+    pub fn validate_model(&self, request: &rouille::Request) -> Result<ModelMetadata> {
+        let upload_model_body: UploadModel = {
+            let mut data: Vec<u8> = vec![];
+            request
+                .data()
+                .expect("Could not get input")
+                .read_to_end(&mut data)?;
+            serde_cbor::from_slice(&data)?
+        };
+        if upload_model_body.model.len() > self.max_model_size {
+            return Err(Error::msg("Model is too big".to_string()));
+        }
+        if upload_model_body.model.is_empty() {
+            return Err(Error::msg("Received no data".to_string()));
+        }
+        Ok(self.model_store.validate_model(
+            &upload_model_body.model,
+            upload_model_body.signature.as_deref(),
+            upload_model_body.model_options()?,
+        )?)
+    }
+
+    pub fn clone_model(&self, request: &rouille::Request) -> Result<SendModelReply> {
+        let mut data_stream = request.data().expect("Could not get the input");
+        let mut data: Vec<u8> = vec![];
+        data_stream.read_to_end(&mut data)?;
+
+        let body: CloneModel = serde_cbor::from_slice(&data)?;
+        let model_id = Uuid::from_str(&body.model_id)?;
+        let model_name = if !body.model_name.is_empty() {
+            Some(body.model_name)
+        } else {
+            None
+        };
+
+        let (model_id, model_hash) = self.model_store.clone_model(model_id, model_name, None)?;
+        Ok(SendModelReply {
+            hash: model_hash.as_ref().to_vec(),
+            model_id: model_id.to_string(),
+        })
     }
 
     pub fn delete_model(&self, request: &rouille::Request) -> Result<()> {
@@ -275,14 +798,88 @@ impl Exchanger {
 
         let model_id = Uuid::from_str(&delete_model_body.model_id)?;
 
-        // Delete the model
-        if self.model_store.delete_model(model_id).is_none() {
+        // Delete the model once the inferences running on it completed
+        self.model_store
+            .drain_and_delete(model_id, self.drain_timeout)?;
+        Ok(())
+    }
+
+    /// Deletes several models at once, without waiting for the inferences
+    /// running on them: those still complete.
+    pub fn delete_models(&self, request: &rouille::Request) -> Result<DeleteModelsReply> {
+        let mut data_stream = request.data().expect("Could not get the input");
+        let mut data: Vec<u8> = vec![];
+        data_stream.read_to_end(&mut data)?;
+
+        let delete_models_body: DeleteModels = serde_cbor::from_slice(&data)?;
+        let model_ids = delete_models_body
+            .model_ids
+            .iter()
+            .map(|model_id| Uuid::from_str(model_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let deleted = self.model_store.delete_models(&model_ids);
+        Ok(DeleteModelsReply {
+            deleted: deleted.iter().map(Option::is_some).collect(),
+        })
+    }
+
+    pub fn set_model_log_level(&self, request: &rouille::Request) -> Result<()> {
+        let mut data_stream = request.data().expect("Could not get the input");
+        let mut data: Vec<u8> = vec![];
+        data_stream.read_to_end(&mut data)?;
+
+        let body: SetModelLogLevel = serde_cbor::from_slice(&data)?;
+        let model_id = Uuid::from_str(&body.model_id)?;
+        let log_level = parse_log_level(body.log_level.as_deref())?;
+
+        if !self.model_store.set_model_log_level(model_id, log_level) {
             error!("Model doesn't exist");
             return Err(Error::msg("Model doesn't exist".to_string()));
         }
         Ok(())
     }
 
+    /// Validates an input against its model and keeps it for the later
+    /// requests, which reference it by the returned handle.
+    pub fn register_input(&self, request: &rouille::Request) -> Result<RegisterInputReply> {
+        let input_cache = self
+            .input_cache
+            .as_ref()
+            .ok_or_else(|| Error::msg("Inputs are not cached on this server"))?;
+        let mut data_stream = request.data().expect("Could not get the input");
+        let mut data: Vec<u8> = vec![];
+        data_stream.read_to_end(&mut data)?;
+
+        let mut body: RegisterInput = serde_cbor::from_slice(&data)?;
+        if body.input.bytes_data.len() > self.max_input_size {
+            return Err(Error::msg("Input too big".to_string()));
+        }
+        let model_id = Uuid::from_str(&body.model_id)?;
+        body.endianness.convert(&mut body.input);
+        let handle = input_cache.register(&self.model_store, model_id, body.input)?;
+        Ok(RegisterInputReply {
+            handle: handle.to_string(),
+        })
+    }
+
+    pub fn reset_circuit_breaker(&self, request: &rouille::Request) -> Result<()> {
+        let mut data_stream = request.data().expect("Could not get the input");
+        let mut data: Vec<u8> = vec![];
+        data_stream.read_to_end(&mut data)?;
+
+        let body: ResetCircuitBreaker = serde_cbor::from_slice(&data)?;
+        let model_id = Uuid::from_str(&body.model_id)?;
+
+        if !self.model_store.reset_circuit_breaker(model_id) {
+            error!("Model doesn't exist or has no circuit breaker");
+            return Err(Error::msg(
+                "Model doesn't exist or has no circuit breaker".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     pub fn respond<Reply: serde::Serialize>(
         &self,
         _rq: &rouille::Request,
@@ -293,15 +890,45 @@ impl Exchanger {
                 "application/cbor",
                 serde_cbor::to_vec(&reply).unwrap(),
             ),
-            Err(e) => rouille::Response::from_data(
-                "application/cbor",
-                serde_cbor::to_vec(&format!("{:?}", &e)).unwrap(),
-            )
-            .with_status_code(500),
+            Err(e) => {
+                let status_code = match e.downcast_ref::<ModelStoreError>() {
+                    Some(err) => err.status_code(),
+                    None if e.is::<KeyReused>() => 409,
+                    None => 500,
+                };
+                rouille::Response::from_data(
+                    "application/cbor",
+                    serde_cbor::to_vec(&format!("{:?}", &e)).unwrap(),
+                )
+                .with_status_code(status_code)
+            }
         }
     }
 }
 
+fn parse_log_level(log_level: Option<&str>) -> Result<Option<LevelFilter>> {
+    log_level
+        .map(|level| {
+            LevelFilter::from_str(level)
+                .map_err(|_| Error::msg(format!("Invalid log level {level}")))
+        })
+        .transpose()
+}
+
+/// Hash identifying a set of input tensors, metadata included.
+fn inputs_digest(inputs: &[impl Borrow<SerializedTensor>]) -> Result<Vec<u8>> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for tensor in inputs {
+        let tensor = tensor.borrow();
+        let info = serde_cbor::to_vec(&tensor.info)?;
+        context.update(&(info.len() as u64).to_le_bytes());
+        context.update(&info);
+        context.update(&(tensor.bytes_data.len() as u64).to_le_bytes());
+        context.update(&tensor.bytes_data);
+    }
+    Ok(context.finish().as_ref().to_vec())
+}
+
 #[allow(dead_code)]
 pub fn bench(repeats: usize, samples: usize, f: impl Fn()) -> Result<()> {
     let mut results = vec![];
@@ -0,0 +1,142 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+
+enum State<V> {
+    Running,
+    Done(V),
+    // the leader panicked before producing a value
+    Abandoned,
+}
+
+struct Pending<V> {
+    state: Mutex<State<V>>,
+    changed: Condvar,
+}
+
+/// Shares one computation between concurrent callers using the same key.
+///
+/// The first caller for a key runs the computation, callers arriving while it
+/// is running wait for its result instead of running it again. Once the value
+/// is produced the key is forgotten: this is not a cache.
+pub(crate) struct Coalescer<K, V> {
+    pending: Mutex<HashMap<K, Arc<Pending<V>>>>,
+}
+
+// Removes the pending entry even if the leader panics, so that the followers
+// never wait forever.
+struct LeaderGuard<'a, K: Eq + Hash, V> {
+    coalescer: &'a Coalescer<K, V>,
+    key: &'a K,
+    pending: &'a Pending<V>,
+}
+
+impl<K: Eq + Hash, V> Drop for LeaderGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.coalescer.pending.lock().unwrap().remove(self.key);
+        let mut state = self.pending.state.lock().unwrap();
+        if let State::Running = *state {
+            *state = State::Abandoned;
+        }
+        self.pending.changed.notify_all();
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Coalescer<K, V> {
+    pub fn new() -> Self {
+        Coalescer {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn run(&self, key: K, fun: impl FnOnce() -> V) -> V {
+        let (pending, leader) = match self.pending.lock().unwrap().entry(key.clone()) {
+            Entry::Occupied(entry) => (Arc::clone(entry.get()), false),
+            Entry::Vacant(entry) => {
+                let pending = Arc::new(Pending {
+                    state: Mutex::new(State::Running),
+                    changed: Condvar::new(),
+                });
+                (Arc::clone(entry.insert(pending)), true)
+            }
+        };
+
+        if leader {
+            let _guard = LeaderGuard {
+                coalescer: self,
+                key: &key,
+                pending: &pending,
+            };
+            let value = fun();
+            *pending.state.lock().unwrap() = State::Done(value.clone());
+            return value;
+        }
+
+        let mut state = pending.state.lock().unwrap();
+        loop {
+            match &*state {
+                State::Running => state = pending.changed.wait(state).unwrap(),
+                State::Done(value) => return value.clone(),
+                State::Abandoned => {
+                    drop(state);
+                    return fun();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn concurrent_identical_calls_run_once() {
+        let coalescer = Arc::new(Coalescer::<u32, u32>::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let leader = thread::spawn({
+            let coalescer = Arc::clone(&coalescer);
+            let runs = Arc::clone(&runs);
+            move || {
+                coalescer.run(1, || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    started_tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(200));
+                    42
+                })
+            }
+        });
+        started_rx.recv().unwrap();
+        let follower = coalescer.run(1, || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            0
+        });
+
+        assert_eq!(leader.join().unwrap(), 42);
+        assert_eq!(follower, 42);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // the key is forgotten once the value was produced
+        assert_eq!(coalescer.run(1, || 7), 7);
+    }
+}
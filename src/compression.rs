@@ -0,0 +1,154 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::client_communication::SerializedTensor;
+use crate::model_store::parse_env;
+use anyhow::{anyhow, Result};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use log::warn;
+use serde_derive::Serialize;
+use std::io::Write;
+use std::str::FromStr;
+
+// outputs smaller than this are sent as is, unless configured otherwise
+const DEFAULT_THRESHOLD: usize = 1 << 20;
+
+/// Algorithm the bytes of the output tensors are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Deflate,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(anyhow!("unknown compression {:?}", s)),
+        }
+    }
+}
+
+/// Compresses the outputs of the inferences whose outputs weigh more than
+/// `threshold` bytes in total.
+///
+/// The outputs are encrypted after they are compressed, so the length of the
+/// ciphertext tells how well they compressed. Like in BREACH, an observer of
+/// the traffic learns about confidential outputs from it, the more so when
+/// it can influence the inputs. Only enable it for outputs whose
+/// compressibility is not sensitive.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OutputCompression {
+    algorithm: Compression,
+    threshold: usize,
+}
+
+impl OutputCompression {
+    pub fn new(algorithm: Compression, threshold: usize) -> Self {
+        OutputCompression {
+            algorithm,
+            threshold,
+        }
+    }
+
+    /// Enabled by `BLINDAI_OUTPUT_COMPRESSION`, "gzip" or "deflate", above
+    /// `BLINDAI_OUTPUT_COMPRESSION_THRESHOLD` bytes.
+    pub fn from_env() -> Option<Self> {
+        let algorithm = std::env::var("BLINDAI_OUTPUT_COMPRESSION").ok()?;
+        let algorithm = match Compression::from_str(&algorithm) {
+            Ok(algorithm) => algorithm,
+            Err(err) => {
+                warn!("Outputs are not compressed: {}", err);
+                return None;
+            }
+        };
+        let threshold =
+            parse_env("BLINDAI_OUTPUT_COMPRESSION_THRESHOLD").unwrap_or(DEFAULT_THRESHOLD);
+        Some(Self::new(algorithm, threshold))
+    }
+
+    /// Compresses the bytes of every output if they are large enough, and
+    /// tells with which algorithm.
+    pub fn compress(&self, outputs: &mut [SerializedTensor]) -> Result<Option<Compression>> {
+        let size: usize = outputs.iter().map(|output| output.bytes_data.len()).sum();
+        if size <= self.threshold {
+            return Ok(None);
+        }
+        for output in outputs {
+            output.bytes_data = match self.algorithm {
+                Compression::Gzip => {
+                    let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                    encoder.write_all(&output.bytes_data)?;
+                    encoder.finish()?
+                }
+                Compression::Deflate => {
+                    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+                    encoder.write_all(&output.bytes_data)?;
+                    encoder.finish()?
+                }
+            };
+        }
+        Ok(Some(self.algorithm))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_communication::TensorInfo;
+    use crate::model::ModelDatumType;
+    use flate2::read::{DeflateDecoder, GzDecoder};
+    use std::io::Read;
+
+    #[test]
+    fn large_outputs_round_trip() {
+        let mask: Vec<u8> = (0..1 << 16).map(|i| (i / 256) as u8).collect();
+        let output = SerializedTensor {
+            info: TensorInfo {
+                fact: vec![mask.len()],
+                datum_type: ModelDatumType::U8,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: mask.clone(),
+        };
+
+        let mut small = vec![output.clone()];
+        let compression = OutputCompression::new(Compression::Gzip, mask.len());
+        assert_eq!(compression.compress(&mut small).unwrap(), None);
+        assert_eq!(small[0].bytes_data, mask);
+
+        for algorithm in [Compression::Gzip, Compression::Deflate] {
+            let mut outputs = vec![output.clone()];
+            let compression = OutputCompression::new(algorithm, 1024);
+            assert_eq!(compression.compress(&mut outputs).unwrap(), Some(algorithm));
+            let compressed = &outputs[0].bytes_data[..];
+            assert!(compressed.len() < mask.len() / 10);
+
+            let mut decompressed = vec![];
+            match algorithm {
+                Compression::Gzip => GzDecoder::new(compressed).read_to_end(&mut decompressed),
+                Compression::Deflate => {
+                    DeflateDecoder::new(compressed).read_to_end(&mut decompressed)
+                }
+            }
+            .unwrap();
+            assert_eq!(decompressed, mask);
+        }
+    }
+}
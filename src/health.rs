@@ -0,0 +1,170 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::client_communication::SerializedTensor;
+use crate::model::{InferenceModel, InferenceOptions, ModelOptions};
+use anyhow::{bail, Result};
+use log::{error, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Proves that the inference path works by running a fixed inference on a
+/// canary model, outside of the model store.
+///
+/// The server is ready once the canary ran, and stays ready as long as it
+/// keeps producing the outputs of its first run. It is never ready with a
+/// canary that could not be loaded.
+pub(crate) struct HealthCheck {
+    // the model and its inputs, none if the model could not be loaded
+    canary: Option<(InferenceModel, Vec<SerializedTensor>)>,
+    // outputs of the first successful run
+    reference: Mutex<Option<Vec<Vec<u8>>>>,
+    ready: AtomicBool,
+}
+
+impl HealthCheck {
+    /// Loads the canary from the file named by `BLINDAI_HEALTH_CHECK_MODEL`.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("BLINDAI_HEALTH_CHECK_MODEL").ok()?;
+        Some(Self::from_path(&path))
+    }
+
+    /// Loads the canary from `path`, a model that can't be loaded gives a
+    /// check that always fails.
+    pub fn from_path(path: &str) -> Self {
+        let loaded = std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| {
+                let model_hash = ring::digest::digest(&ring::digest::SHA256, &bytes);
+                let model = InferenceModel::load_model(
+                    &bytes,
+                    Uuid::new_v4(),
+                    Some("health_check".to_string()),
+                    model_hash,
+                    &ModelOptions {
+                        optimize: true,
+                        ..Default::default()
+                    },
+                )?;
+                Self::new(model)
+            });
+        match loaded {
+            Ok(health_check) => health_check,
+            Err(err) => {
+                error!("Could not load the health check model {}: {}", path, err);
+                HealthCheck {
+                    canary: None,
+                    reference: Mutex::new(None),
+                    ready: AtomicBool::new(false),
+                }
+            }
+        }
+    }
+
+    /// The canary is fed zero-filled inputs.
+    pub fn new(model: InferenceModel) -> Result<Self> {
+        let inputs = model.zero_inputs()?;
+        Ok(HealthCheck {
+            canary: Some((model, inputs)),
+            reference: Mutex::new(None),
+            ready: AtomicBool::new(false),
+        })
+    }
+
+    /// Spawns the thread running the canary every `period`.
+    pub fn spawn(self: &Arc<Self>, period: Duration) {
+        let health_check = Arc::clone(self);
+        thread::spawn(move || loop {
+            if let Err(err) = health_check.run_health_inference() {
+                warn!("Health check failed: {}", err);
+            }
+            thread::sleep(period);
+        });
+    }
+
+    /// Runs the canary and updates the readiness with the outcome.
+    pub fn run_health_inference(&self) -> Result<()> {
+        let result = self.check();
+        self.ready.store(result.is_ok(), Ordering::Relaxed);
+        result
+    }
+
+    fn check(&self) -> Result<()> {
+        let (model, inputs) = match &self.canary {
+            Some(canary) => canary,
+            None => bail!("the canary model could not be loaded"),
+        };
+        let (outputs, _) = model.run_inference(inputs, &InferenceOptions::default())?;
+        let outputs: Vec<_> = outputs
+            .into_iter()
+            .map(|tensor| tensor.bytes_data)
+            .collect();
+        let mut reference = self.reference.lock().unwrap();
+        match &*reference {
+            Some(reference) if *reference != outputs => {
+                bail!("the canary model produced different outputs than on its first run")
+            }
+            Some(_) => {}
+            None => *reference = Some(outputs),
+        }
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::test_graphs::*;
+
+    fn canary(graph: &[u8]) -> InferenceModel {
+        InferenceModel::load_model(
+            graph,
+            Uuid::new_v4(),
+            None,
+            ring::digest::digest(&ring::digest::SHA256, graph),
+            &ModelOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn broken_canary_is_not_ready() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[4])],
+            vec![value_info("relu", FLOAT, &[4])],
+        );
+        let health_check = HealthCheck::new(canary(&graph)).unwrap();
+        assert!(!health_check.is_ready());
+        health_check.run_health_inference().unwrap();
+        health_check.run_health_inference().unwrap();
+        assert!(health_check.is_ready());
+
+        // no node may produce its 16 bytes output anymore
+        let broken = HealthCheck::new(canary(&graph).with_max_tensor_size(8)).unwrap();
+        assert!(broken.run_health_inference().is_err());
+        assert!(!broken.is_ready());
+
+        let unloadable = HealthCheck::from_path("/nonexistent/canary.onnx");
+        assert!(unloadable.run_health_inference().is_err());
+        assert!(!unloadable.is_ready());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entries<K, V> {
+    values: HashMap<K, (Instant, V)>,
+    // keys in insertion order, the oldest first
+    order: VecDeque<K>,
+}
+
+/// A request carrying the idempotency key of an earlier request, without
+/// being the same request.
+#[derive(Debug)]
+pub(crate) struct KeyReused;
+
+impl std::fmt::Display for KeyReused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The idempotency key was used by a different request")
+    }
+}
+
+impl std::error::Error for KeyReused {}
+
+/// Remembers the results of the requests carrying an idempotency key, so
+/// that a request replayed by the client gets the original result back.
+///
+/// At most `capacity` results are kept, for at most `ttl`. Only completed
+/// requests are remembered: a replay arriving while the original request is
+/// still running is served again.
+pub(crate) struct IdempotencyCache<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> IdempotencyCache<K, V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        IdempotencyCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        match entries.values.get(key) {
+            Some((inserted, value)) if inserted.elapsed() < self.ttl => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Remembers the result of a request, the first result stored for a key
+    /// is the one replayed.
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { values, order } = &mut *entries;
+        while let Some(oldest) = order.front() {
+            let expired = values[oldest].0.elapsed() >= self.ttl;
+            if !expired && values.len() < self.capacity {
+                break;
+            }
+            values.remove(oldest);
+            order.pop_front();
+        }
+        if values.len() < self.capacity && !values.contains_key(&key) {
+            values.insert(key.clone(), (Instant::now(), value));
+            order.push_back(key);
+        }
+    }
+
+    /// Forgets the results whose key doesn't satisfy `keep`.
+    pub fn retain(&self, keep: impl Fn(&K) -> bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { values, order } = &mut *entries;
+        values.retain(|key, _| keep(key));
+        order.retain(|key| values.contains_key(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_and_expiring() {
+        let cache = IdempotencyCache::new(Duration::from_secs(3600), 2);
+        cache.insert("a", 1);
+        cache.insert("a", 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.insert("b", 3);
+        cache.insert("c", 4);
+        // the oldest key made room for the new one
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(3));
+        assert_eq!(cache.get(&"c"), Some(4));
+
+        let cache = IdempotencyCache::new(Duration::ZERO, 2);
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), None);
+        cache.insert("b", 2);
+        assert_eq!(cache.entries.lock().unwrap().values.len(), 1);
+
+        let cache = IdempotencyCache::new(Duration::from_secs(3600), 2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.retain(|key| *key != "a");
+        assert_eq!(cache.get(&"a"), None);
+        // the forgotten key made room
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), Some(2));
+        assert_eq!(cache.get(&"c"), Some(3));
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::client_communication::SerializedTensor;
+use crate::model_store::{parse_env, ModelStore};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(600);
+
+struct CachedInput {
+    model_id: Uuid,
+    inserted: Instant,
+    tensor: Arc<SerializedTensor>,
+}
+
+/// Inputs registered once and then referenced by handle in the requests,
+/// e.g. a large context tensor shared by many queries that only differ by
+/// a small one.
+///
+/// An input is bound to the model it was validated against, and kept for
+/// at most `ttl`. At most `capacity` inputs are kept: registering one more
+/// fails until some expire.
+pub(crate) struct InputCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<Uuid, CachedInput>>,
+}
+
+impl InputCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        InputCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Enabled by `BLINDAI_INPUT_CACHE_CAPACITY`, the inputs are kept for
+    /// `BLINDAI_INPUT_CACHE_TTL_SECS`.
+    pub fn from_env() -> Option<Self> {
+        let capacity = parse_env("BLINDAI_INPUT_CACHE_CAPACITY")?;
+        let ttl =
+            parse_env("BLINDAI_INPUT_CACHE_TTL_SECS").map_or(DEFAULT_TTL, Duration::from_secs);
+        Some(Self::new(ttl, capacity))
+    }
+
+    /// Keeps an input already validated against `model_id`, and returns
+    /// its handle.
+    pub fn insert(&self, model_id: Uuid, tensor: SerializedTensor) -> Result<Uuid> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, input| input.inserted.elapsed() < self.ttl);
+        if entries.len() >= self.capacity {
+            bail!("The input cache is full");
+        }
+        let handle = Uuid::new_v4();
+        entries.insert(
+            handle,
+            CachedInput {
+                model_id,
+                inserted: Instant::now(),
+                tensor: Arc::new(tensor),
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Validates an input against `model_id` and keeps it, see `insert`.
+    ///
+    /// The input is kept while the store still holds the model: the model
+    /// can't be removed in between, and `forget_model` run on its removal
+    /// finds the input.
+    pub fn register(
+        &self,
+        model_store: &ModelStore,
+        model_id: Uuid,
+        tensor: SerializedTensor,
+    ) -> Result<Uuid> {
+        model_store.try_use_model(model_id, |model| {
+            model.check_named_input(&tensor)?;
+            self.insert(model_id, tensor)
+        })?
+    }
+
+    /// Drops the inputs bound to a model that was removed or replaced.
+    pub fn forget_model(&self, model_id: Uuid) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, input| input.model_id != model_id);
+    }
+
+    /// The input registered under `handle` for `model_id`.
+    pub fn get(&self, handle: Uuid, model_id: Uuid) -> Result<Arc<SerializedTensor>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&handle) {
+            Some(input) if input.inserted.elapsed() < self.ttl && input.model_id == model_id => {
+                Ok(Arc::clone(&input.tensor))
+            }
+            _ => bail!("No cached input {} for this model", handle),
+        }
+    }
+
+    /// The inputs referenced by a request, in the order of the handles.
+    pub fn resolve(
+        &self,
+        handles: &[String],
+        model_id: Uuid,
+    ) -> Result<Vec<Arc<SerializedTensor>>> {
+        handles
+            .iter()
+            .map(|handle| {
+                let handle = Uuid::from_str(handle)
+                    .map_err(|_| anyhow!("Invalid cached input handle {:?}", handle))?;
+                self.get(handle, model_id)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_communication::TensorInfo;
+    use crate::model::test_graphs::*;
+    use crate::model::{InferenceModel, InferenceOptions, ModelDatumType, ModelOptions};
+    use crate::model_store::ModelStoreConfig;
+
+    #[test]
+    fn bound_to_the_model_and_expiring() {
+        let tensor = SerializedTensor {
+            info: TensorInfo {
+                fact: vec![1],
+                datum_type: ModelDatumType::U8,
+                node_name: Some("context".into()),
+                layout: None,
+            },
+            bytes_data: vec![42],
+        };
+        let model_id = Uuid::new_v4();
+        let cache = InputCache::new(Duration::from_secs(3600), 1);
+        let handle = cache.insert(model_id, tensor.clone()).unwrap();
+        assert_eq!(cache.get(handle, model_id).unwrap().bytes_data, [42]);
+        assert!(cache.get(handle, Uuid::new_v4()).is_err());
+        assert!(cache.get(Uuid::new_v4(), model_id).is_err());
+        assert!(cache.insert(model_id, tensor.clone()).is_err());
+        // gone with the model
+        cache.forget_model(model_id);
+        assert!(cache.get(handle, model_id).is_err());
+        cache.insert(model_id, tensor.clone()).unwrap();
+
+        let cache = InputCache::new(Duration::ZERO, 1);
+        let handle = cache.insert(model_id, tensor.clone()).unwrap();
+        assert!(cache.get(handle, model_id).is_err());
+        // the expired input made room
+        cache.insert(model_id, tensor).unwrap();
+    }
+
+    #[test]
+    fn no_input_outlives_a_racing_delete() {
+        let graph = model(
+            vec![node("Relu", &["context"], &["relu"])],
+            vec![value_info("context", FLOAT, &[1])],
+            vec![value_info("relu", FLOAT, &[1])],
+        );
+        let tensor = SerializedTensor {
+            info: TensorInfo {
+                fact: vec![1],
+                datum_type: ModelDatumType::F32,
+                node_name: Some("context".into()),
+                layout: None,
+            },
+            bytes_data: 1.0f32.to_le_bytes().to_vec(),
+        };
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let cache = Arc::new(InputCache::new(Duration::from_secs(3600), 1024));
+        {
+            let cache = Arc::clone(&cache);
+            store.on_model_removed(move |model_id| cache.forget_model(model_id));
+        }
+        let barrier = std::sync::Barrier::new(2);
+        for _ in 0..200 {
+            let (model_id, _) = store
+                .add_model(&graph, None, ModelOptions::default())
+                .unwrap();
+            std::thread::scope(|scope| {
+                let registered = scope.spawn(|| {
+                    barrier.wait();
+                    cache.register(&store, model_id, tensor.clone())
+                });
+                barrier.wait();
+                store.delete_model(model_id).unwrap();
+                if let Ok(handle) = registered.join().unwrap() {
+                    assert!(cache.get(handle, model_id).is_err());
+                }
+            });
+        }
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queries_against_a_cached_context() {
+        let graph = model(
+            vec![node("Add", &["query", "context"], &["sum"])],
+            vec![
+                value_info("query", FLOAT, &[2]),
+                value_info("context", FLOAT, &[2]),
+            ],
+            vec![value_info("sum", FLOAT, &[2])],
+        );
+        let model_id = Uuid::new_v4();
+        let model = InferenceModel::load_model(
+            &graph,
+            model_id,
+            None,
+            ring::digest::digest(&ring::digest::SHA256, &graph),
+            &ModelOptions::default(),
+        )
+        .unwrap();
+        let tensor = |name: Option<&str>, values: [f32; 2]| SerializedTensor {
+            info: TensorInfo {
+                fact: vec![2],
+                datum_type: ModelDatumType::F32,
+                node_name: name.map(str::to_owned),
+                layout: None,
+            },
+            bytes_data: values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        };
+
+        let context = tensor(Some("context"), [10.0, 20.0]);
+        model.check_named_input(&context).unwrap();
+        let cache = InputCache::new(Duration::from_secs(3600), 8);
+        let handle = cache.insert(model_id, context).unwrap().to_string();
+        for query in [1.0, 2.0, 3.0] {
+            let mut inputs = vec![Arc::new(tensor(None, [query, -query]))];
+            inputs.extend(cache.resolve(&[handle.clone()], model_id).unwrap());
+            // shared with the cache rather than copied
+            let cached = cache.get(handle.parse().unwrap(), model_id).unwrap();
+            assert!(Arc::ptr_eq(&inputs[1], &cached));
+            let (outputs, _) = model
+                .run_inference(&inputs, &InferenceOptions::default())
+                .unwrap();
+            let expected: Vec<u8> = [10.0 + query, 20.0 - query]
+                .iter()
+                .flat_map(|value: &f32| value.to_le_bytes())
+                .collect();
+            assert_eq!(outputs[0].bytes_data, expected);
+        }
+        assert!(cache.resolve(&["not a handle".into()], model_id).is_err());
+
+        // cached inputs must name an input of the model and match its fact
+        assert!(model.check_named_input(&tensor(None, [0.0, 0.0])).is_err());
+        assert!(model
+            .check_named_input(&tensor(Some("sum"), [0.0, 0.0]))
+            .is_err());
+        let mut short = tensor(Some("context"), [0.0, 0.0]);
+        short.info.fact = vec![1];
+        short.bytes_data.truncate(4);
+        assert!(model.check_named_input(&short).is_err());
+    }
+}
@@ -16,17 +16,28 @@
 
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+mod circuit_breaker;
+mod coalescer;
+mod compression;
+mod health;
+mod idempotency;
 mod identity;
+mod input_cache;
+mod metrics;
 mod model;
 mod model_store;
+mod reader_gate;
+mod signature;
 use crate::client_communication::Exchanger;
 use anyhow::Result;
 use model_store::ModelStore;
 mod client_communication;
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, LevelFilter};
 mod telemetry;
 mod ureq_dns_resolver;
+mod watchdog;
 use telemetry::Telemetry;
 
 // ra
@@ -46,11 +57,15 @@ struct GetCollateralRequest {
     quote: Vec<u8>,
 }
 
+/// Seconds after which a running inference is flagged and its result dropped.
+const INFERENCE_DEADLINE: &str = "BLINDAI_INFERENCE_DEADLINE_SECS";
+
 lazy_static! {
     static ref EXCHANGER: Arc<Exchanger> = Arc::new(Exchanger::new(
         Arc::new(ModelStore::new()),
         1_000_000_000,
         1_000_000,
+        Duration::from_secs(model_store::parse_env(INFERENCE_DEADLINE).unwrap_or(300)),
     ));
     pub static ref TELEMETRY_CHANNEL: Arc<Telemetry> = Arc::new(Telemetry::new().unwrap());
 }
@@ -118,10 +133,15 @@ fn main() -> Result<()> {
 
     // Make debugging easier by enabling rust backtrace inside enclave
     std::env::set_var("RUST_BACKTRACE", "full");
+    // Models with a log level override do their own filtering
     #[cfg(debug_assertions)]
-    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
+        .filter_module(model::MODEL_LOG_TARGET, LevelFilter::Trace)
+        .init();
     #[cfg(not(debug_assertions))]
-    env_logger::Builder::from_env(Env::default().default_filter_or("error")).init();
+    env_logger::Builder::from_env(Env::default().default_filter_or("error"))
+        .filter_module(model::MODEL_LOG_TARGET, LevelFilter::Trace)
+        .init();
 
     let certificate_with_secret = identity::create_tls_certificate()?;
     let enclave_cert_der = Arc::new(certificate_with_secret.serialize_der()?);
@@ -206,15 +226,68 @@ fn main() -> Result<()> {
 
     let router_management = |request: &rouille::Request| {
         rouille::router!(request,
+            (GET) (/capabilities) => {
+                EXCHANGER.respond(request, Ok(EXCHANGER.capabilities()))
+            },
+
+            (GET) (/health) => {
+                let health = EXCHANGER.health();
+                let status_code = if health.ready { 200 } else { 503 };
+                EXCHANGER.respond(request, Ok(health)).with_status_code(status_code)
+            },
+
+            (GET) (/stats) => {
+                EXCHANGER.respond(request, Ok(EXCHANGER.stats()))
+            },
+
+            (GET) (/models) => {
+                EXCHANGER.respond(request, Ok(EXCHANGER.list_models()))
+            },
+
             (POST) (/upload) => {
                 let reply = EXCHANGER.send_model(request);
                 EXCHANGER.respond(request, reply)
             },
 
+            (POST) (/upload_stream) => {
+                let reply = EXCHANGER.stream_model(request);
+                EXCHANGER.respond(request, reply)
+            },
+
+            (POST) (/validate) => {
+                let reply = EXCHANGER.validate_model(request);
+                EXCHANGER.respond(request, reply)
+            },
+
+            (POST) (/clone) => {
+                let reply = EXCHANGER.clone_model(request);
+                EXCHANGER.respond(request, reply)
+            },
+
+            (POST) (/replace) => {
+                let reply = EXCHANGER.replace_model(request);
+                EXCHANGER.respond(request, reply)
+            },
+
             (POST) (/delete) => {
                 let reply = EXCHANGER.delete_model(request);
                 EXCHANGER.respond(request, reply)
             },
+
+            (POST) (/delete_batch) => {
+                let reply = EXCHANGER.delete_models(request);
+                EXCHANGER.respond(request, reply)
+            },
+
+            (POST) (/set_log_level) => {
+                let reply = EXCHANGER.set_model_log_level(request);
+                EXCHANGER.respond(request, reply)
+            },
+
+            (POST) (/reset_circuit_breaker) => {
+                let reply = EXCHANGER.reset_circuit_breaker(request);
+                EXCHANGER.respond(request, reply)
+            },
             _ => rouille::Response::empty_404()
         )
     };
@@ -246,6 +319,15 @@ fn main() -> Result<()> {
                 let reply = EXCHANGER.run_model(request);
                 EXCHANGER.respond(request, reply)
             },
+
+            (POST) (/register_input) => {
+                let reply = EXCHANGER.register_input(request);
+                EXCHANGER.respond(request, reply)
+            },
+
+            (GET) (/models_merkle_root) => {
+                EXCHANGER.respond(request, Ok(EXCHANGER.models_merkle_root()))
+            },
             _ => rouille::Response::empty_404()
         )
     };
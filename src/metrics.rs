@@ -0,0 +1,45 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use log::info;
+
+/// Receives the metrics of the store as they happen, to push them to an
+/// external system. Every method does nothing unless implemented.
+///
+/// The store calls the sink while holding its locks: implementations should
+/// hand the values over, e.g. to a channel, rather than send them.
+pub trait MetricsSink: Send + Sync {
+    fn incr(&self, _counter: &str, _labels: &[(&str, &str)]) {}
+
+    fn set_gauge(&self, _gauge: &str, _value: f64, _labels: &[(&str, &str)]) {}
+
+    fn observe(&self, _histogram: &str, _value: f64, _labels: &[(&str, &str)]) {}
+}
+
+/// Logs the metrics, for deployments without a metrics backend.
+pub struct LogMetricsSink;
+
+impl MetricsSink for LogMetricsSink {
+    fn incr(&self, counter: &str, labels: &[(&str, &str)]) {
+        info!("metric {} +1 {:?}", counter, labels);
+    }
+
+    fn set_gauge(&self, gauge: &str, value: f64, labels: &[(&str, &str)]) {
+        info!("metric {} = {} {:?}", gauge, value, labels);
+    }
+
+    fn observe(&self, histogram: &str, value: f64, labels: &[(&str, &str)]) {
+        info!("metric {} <- {} {:?}", histogram, value, labels);
+    }
+}
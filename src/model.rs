@@ -14,17 +14,54 @@
 
 use std::vec::Vec;
 
+use crate::circuit_breaker::CircuitBreaker;
 use crate::client_communication::{SerializedTensor, TensorInfo};
-use anyhow::{anyhow, bail, Result};
-use core::hash::Hash;
+use anyhow::{anyhow, bail, Context, Result};
+use core::hash::{Hash, Hasher};
+use log::{warn, Level, LevelFilter};
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use ring::digest::Digest;
 use serde_derive::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use tract_onnx::prelude::{DatumType, TVec, *};
 use uuid::Uuid;
 
 pub type OnnxModel = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
 
+/// Log target used by models with a log level override. The logger lets
+/// everything through for this target, the filtering is done per model.
+pub const MODEL_LOG_TARGET: &str = "blindai::model";
+
+/// Picks the target of a log line emitted in the context of a model: `None`
+/// drops the line, otherwise it goes to the returned target.
+pub fn model_log_target(
+    log_level: Option<LevelFilter>,
+    level: Level,
+    default_target: &'static str,
+) -> Option<&'static str> {
+    match log_level {
+        Some(log_level) if level <= log_level => Some(MODEL_LOG_TARGET),
+        Some(_) => None,
+        None => Some(default_target),
+    }
+}
+
+/// Like `log!`, but honoring the log level override of a model.
+macro_rules! model_log {
+    ($log_level:expr, $level:expr, $($arg:tt)+) => {
+        if let Some(target) = $crate::model::model_log_target($log_level, $level, module_path!()) {
+            log::log!(target: target, $level, $($arg)+);
+        }
+    };
+}
+pub(crate) use model_log;
+
 #[derive(
     Debug, Default, FromPrimitive, PartialEq, Clone, Copy, Eq, Hash, Serialize, Deserialize,
 )]
@@ -44,6 +81,11 @@ pub enum ModelDatumType {
 }
 
 impl ModelDatumType {
+    /// Every datum type the tensor codec handles.
+    pub fn all() -> Vec<ModelDatumType> {
+        (0..).map_while(ModelDatumType::from_u32).collect()
+    }
+
     fn get_datum_type(self) -> DatumType {
         match self {
             ModelDatumType::F32 => f32::datum_type(),
@@ -221,6 +263,77 @@ impl ToLeBytes for &[bool] {
     }
 }
 
+/// Byte order of the tensors exchanged with a client. The codec itself only
+/// handles little-endian values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Converts the values of a tensor between this byte order and the
+    /// little-endian one, the conversion goes both ways.
+    pub fn convert(self, tensor: &mut SerializedTensor) {
+        let width = tensor.info.datum_type.get_datum_type().size_of();
+        if self == Endianness::Big && width > 1 {
+            for value in tensor.bytes_data.chunks_exact_mut(width) {
+                value.reverse();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_deserialize_big_endian() {
+    let mut tensor = SerializedTensor {
+        info: TensorInfo {
+            datum_type: ModelDatumType::F32,
+            fact: vec![3],
+            node_name: None,
+            layout: None,
+        },
+        bytes_data: [0.5f32, -2.0, 1000.0]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect(),
+    };
+    Endianness::Big.convert(&mut tensor);
+    assert_eq!(
+        &Vec::<f32>::from_le_bytes(&tensor.bytes_data).unwrap(),
+        &[0.5f32, -2.0, 1000.0]
+    );
+    Endianness::Big.convert(&mut tensor);
+    assert_eq!(&tensor.bytes_data[..4], &0.5f32.to_be_bytes());
+}
+
+// Values of a tensor as f64, `None` for non numeric tensors.
+fn values_as_f64(tensor: &SerializedTensor) -> Result<Option<Vec<f64>>> {
+    macro_rules! decode {
+        ($t:ty) => {
+            Vec::<$t>::from_le_bytes(&tensor.bytes_data)?
+                .into_iter()
+                .map(|value| value as f64)
+                .collect()
+        };
+    }
+    Ok(Some(match tensor.info.datum_type {
+        ModelDatumType::F32 => decode!(f32),
+        ModelDatumType::F64 => decode!(f64),
+        ModelDatumType::I32 => decode!(i32),
+        ModelDatumType::I64 => decode!(i64),
+        ModelDatumType::U32 => decode!(u32),
+        ModelDatumType::U64 => decode!(u64),
+        ModelDatumType::U8 => decode!(u8),
+        ModelDatumType::U16 => decode!(u16),
+        ModelDatumType::I8 => decode!(i8),
+        ModelDatumType::I16 => decode!(i16),
+        ModelDatumType::Bool => return Ok(None),
+    }))
+}
+
 fn create_tensor<A: tract_core::prelude::Datum>(
     input: &[u8],
     input_fact: &[usize],
@@ -247,196 +360,2722 @@ where
     Ok(slice.to_le_bytes())
 }
 
+/// Per-model settings chosen at upload time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelOptions {
+    pub optimize: bool,
+    /// Version of the input/output schema the model was built for.
+    pub schema_version: u32,
+    /// Whether identical inputs always give identical outputs.
+    pub deterministic: bool,
+    /// Log level override for the lines emitted while the model is used.
+    pub log_level: Option<LevelFilter>,
+    /// Fraction of the inferences of an optimized model served by an
+    /// unoptimized graph, to compare both. Both graphs stay in memory.
+    pub ab_unoptimized_fraction: Option<f64>,
+    /// Layout the image inputs are expected in. Inputs declaring another
+    /// layout are rejected.
+    pub input_layout: Option<TensorLayout>,
+    pub duplicate_output_names: DuplicateOutputNames,
+    /// Bounds the values of the numeric inputs must be within, e.g. [0, 1]
+    /// for normalized images.
+    pub input_range: Option<ValueRange>,
+    /// Longest chain of nodes accepted, set by the store from its
+    /// configuration.
+    pub max_graph_depth: Option<usize>,
+}
+
+/// Inclusive bounds on the values of a tensor.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ValueRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+/// What to do when several outputs of a model have the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateOutputNames {
+    /// Refuse to load the model.
+    Reject,
+    /// Append the output index to the duplicated names.
+    #[default]
+    Suffix,
+}
+
+/// Per-request settings of an inference.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct InferenceOptions {
+    /// Datum type the outputs are cast to before being sent back.
+    pub output_datum_type: Option<ModelDatumType>,
+    /// Allow casts that may lose information, e.g. from float to int.
+    pub allow_lossy_cast: bool,
+    pub top_k: Option<TopK>,
+    /// Bound to the input named `seed` of stochastic models, so that a run
+    /// is reproducible given the same inputs and seed.
+    pub seed: Option<u64>,
+}
+
+/// Only send back the `k` largest values of an output, followed by an output
+/// named `{output}_indices` with their indices.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub struct TopK {
+    /// Name of the output, as sent back by the server.
+    pub output: String,
+    pub k: usize,
+}
+
+impl InferenceOptions {
+    fn check_output_cast(&self, from: DatumType) -> Result<()> {
+        let to = match self.output_datum_type {
+            Some(to) => to.get_datum_type(),
+            None => return Ok(()),
+        };
+        if !self.allow_lossy_cast && !is_lossless_cast(from, to) {
+            bail!(
+                "Casting {:?} outputs to {:?} may lose information, it must be explicitly allowed",
+                from,
+                to
+            );
+        }
+        Ok(())
+    }
+}
+
+// Whether every value of `from` can be represented in `to`.
+fn is_lossless_cast(from: DatumType, to: DatumType) -> bool {
+    if from == to {
+        return true;
+    }
+    if from == DatumType::Bool {
+        return to.is_integer() || to.is_float();
+    }
+    if from.is_float() {
+        return to.is_float() && to.size_of() >= from.size_of();
+    }
+    if !from.is_integer() {
+        return false;
+    }
+    if to.is_float() {
+        let mantissa_bits = match to {
+            DatumType::F16 => 11,
+            DatumType::F32 => 24,
+            _ => 53,
+        };
+        return from.size_of() * 8 - from.is_signed() as usize <= mantissa_bits;
+    }
+    if to.is_unsigned() {
+        return from.is_unsigned() && to.size_of() >= from.size_of();
+    }
+    to.is_signed()
+        && (to.size_of() > from.size_of() || (from.is_signed() && to.size_of() == from.size_of()))
+}
+
+/// Memory layout of an image tensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum TensorLayout {
+    Nchw,
+    Nhwc,
+}
+
+impl std::fmt::Display for TensorLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TensorLayout::Nchw => "NCHW",
+            TensorLayout::Nhwc => "NHWC",
+        })
+    }
+}
+
+/// Which graph of a model served an inference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum GraphVariant {
+    #[default]
+    Optimized,
+    Unoptimized,
+}
+
+#[derive(Debug)]
+struct AbVariant {
+    unoptimized: OnnxModel,
+    fraction: f64,
+    served: AtomicU64,
+}
+
+impl AbVariant {
+    // Spreads the unoptimized runs evenly: the n-th inference goes to the
+    // unoptimized graph when it makes the count of such runs reach the next
+    // integer.
+    fn serve_unoptimized(&self) -> bool {
+        let n = self.served.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.fraction).floor() > (n * self.fraction).floor()
+    }
+}
+
 #[derive(Debug)]
 pub struct InferenceModel {
     pub onnx: Arc<OnnxModel>,
-    #[allow(unused)]
     model_id: Uuid,
     model_name: Option<String>,
     model_hash: Digest,
+    // the log level is tracked separately since it may change
+    options: ModelOptions,
+    // 0 when there is no override, LevelFilter + 1 otherwise
+    log_level: AtomicUsize,
+    // copy of the model, for the inference fallback and the integrity checks
+    model_bytes: Option<Arc<[u8]>>,
+    // rebuild an unoptimized graph from `model_bytes` when an inference fails
+    fallback_on_error: bool,
+    // built from `model_bytes` by the first inference that falls back, `None`
+    // if the bytes could not be loaded
+    fallback_plan: OnceLock<Option<OnnxModel>>,
+    ab_variant: Option<AbVariant>,
+    circuit_breaker: Option<CircuitBreaker>,
+    // largest tensor in bytes a node may produce during an inference
+    max_tensor_size: Option<usize>,
+    // ticks of the store's clock when the model was registered and last used,
+    // to pick the model to evict
+    loaded_at: u64,
+    last_used: AtomicU64,
+    registered_at: Instant,
+    // set by the first successful inference
+    warmed_up: AtomicBool,
+    // approximate bytes taken by the graph: the size of the ONNX it was
+    // loaded from, of which the weights take most
+    graph_size: usize,
+    // uses of the model by the store that have not returned yet
+    in_flight: AtomicUsize,
+    draining: AtomicBool,
 }
 
-impl InferenceModel {
-    #[allow(clippy::too_many_arguments)]
-    pub fn load_model(
-        mut model_data: &[u8],
-        model_id: Uuid,
-        model_name: Option<String>,
-        model_hash: Digest,
-        optimize: bool,
-    ) -> Result<Self> {
-        let model_rec = tract_onnx::onnx()
-            .with_ignore_output_shapes(true)
-            .model_for_read(&mut model_data)?;
-        let onnx = match optimize {
-            true => model_rec.into_optimized()?,
-            false => model_rec.into_typed()?,
-        };
+/// A use of a model, counted as in flight until it is dropped.
+pub struct InFlightUse<'a>(&'a AtomicUsize);
 
-        Ok(InferenceModel {
-            onnx: onnx.into_runnable()?.into(),
-            model_name,
-            model_id,
-            model_hash,
-        })
+impl Drop for InFlightUse<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
+}
 
-    pub fn run_inference(&self, inputs: &[SerializedTensor]) -> Result<Vec<SerializedTensor>> {
-        let mut tensors: Vec<_> = vec![];
-        let outlets = self.onnx.model.input_outlets()?;
-        for tensor in inputs {
-            let tract_tensor = convert_datum!(create_tensor(
-                tensor.info.datum_type.get_datum_type()
-            )(
-                &tensor.bytes_data, tensor.info.fact.as_slice()
-            ))?;
-            if let Some(node_name) = &tensor.info.node_name {
-                let node_id = self.onnx.model.node_id_by_name(node_name)?;
-                let rank = outlets
-                    .iter()
-                    .position(|&outlet| outlet.node == node_id)
-                    .ok_or_else(|| anyhow!("no node with name {}", node_name))?;
-                tensors.insert(rank, tract_tensor);
-            } else {
-                tensors.push(tract_tensor);
+/// How ready a model is to serve. Models enter the store loaded, they are
+/// warmed up once an inference succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelStatus {
+    Loaded,
+    WarmedUp,
+    /// The circuit breaker of the model is open.
+    Degraded,
+    /// The model is about to be deleted, it takes no new requests.
+    Draining,
+}
+
+/// Why tract could not load a model, detailed enough for the uploader to fix
+/// the export.
+#[derive(Debug)]
+pub struct LoadFailure {
+    /// Operator of the node tract failed on.
+    pub op: Option<String>,
+    pub node: Option<String>,
+    pub reason: String,
+}
+
+impl LoadFailure {
+    fn from_tract(err: anyhow::Error) -> Self {
+        // tract reports translation failures as `Translating node #2 "name" Op ...`
+        let message = err.to_string();
+        let located = message
+            .strip_prefix("Translating node #")
+            .and_then(|rest| rest.split_once(" \""))
+            .and_then(|(_, rest)| rest.split_once("\" "));
+        match located {
+            Some((node, rest)) => LoadFailure {
+                op: rest.split(' ').next().map(|op| op.to_string()),
+                node: Some(node.to_string()),
+                reason: err.root_cause().to_string(),
+            },
+            None => LoadFailure {
+                op: None,
+                node: None,
+                reason: format!("{:#}", err),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for LoadFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LoadFailure: ")?;
+        if let Some(node) = &self.node {
+            write!(f, "node {} ", node)?;
+        }
+        if let Some(op) = &self.op {
+            write!(f, "({}) ", op)?;
+        }
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for LoadFailure {}
+
+/// Rewrites a model in a canonical form, so that exports of the same model
+/// that only differ by their metadata or by the order of their nodes and
+/// initializers get the same bytes, and the same hash. The names of the
+/// nodes are kept: they point at the failing node in errors.
+pub fn canonicalize(model_data: &[u8]) -> Result<Vec<u8>> {
+    use prost::Message;
+    let mut model =
+        tract_onnx::pb::ModelProto::decode(model_data).context("Reading proto model")?;
+    model.producer_name.clear();
+    model.producer_version.clear();
+    model.domain.clear();
+    model.model_version = 0;
+    model.doc_string.clear();
+    model.metadata_props.clear();
+    model.opset_import.sort_by(|a, b| a.domain.cmp(&b.domain));
+    if let Some(graph) = &mut model.graph {
+        canonicalize_graph(graph);
+    }
+    Ok(model.encode_to_vec())
+}
+
+fn canonicalize_graph(graph: &mut tract_onnx::pb::GraphProto) {
+    graph.name.clear();
+    graph.doc_string.clear();
+    graph.initializer.sort_by(|a, b| a.name.cmp(&b.name));
+    graph.value_info.sort_by(|a, b| a.name.cmp(&b.name));
+    for tensor in &mut graph.initializer {
+        tensor.doc_string.clear();
+    }
+    for info in graph
+        .input
+        .iter_mut()
+        .chain(&mut graph.output)
+        .chain(&mut graph.value_info)
+    {
+        info.doc_string.clear();
+    }
+    for node in &mut graph.node {
+        node.doc_string.clear();
+        node.attribute.sort_by(|a, b| a.name.cmp(&b.name));
+        for attribute in &mut node.attribute {
+            attribute.doc_string.clear();
+            for subgraph in attribute.g.iter_mut().chain(&mut attribute.graphs) {
+                canonicalize_graph(subgraph);
             }
         }
-        let mut result = self.onnx.run(TVec::from_vec(tensors.clone()))?;
-        result = result
-            .into_iter()
-            .map(|tensor| {
-                if tensor.datum_type() == DatumType::TDim {
-                    Ok(tensor.cast_to::<i64>()?.into_owned().into())
-                } else {
-                    Ok(tensor)
-                }
-            })
-            .collect::<TractResult<_>>()?;
-        let mut outputs: Vec<SerializedTensor> = vec![];
-        let output_names = self.get_output_names();
-        for (i, tensor) in result.iter().enumerate() {
-            outputs.push(SerializedTensor {
-                info: TensorInfo {
-                    datum_type: ModelDatumType::try_from(tensor.datum_type())?,
-                    fact: tensor.shape().to_owned(),
-                    node_name: Some(output_names[i].clone()),
-                },
-                bytes_data: convert_datum!(convert_tensor(tensor.datum_type())(tensor))?,
-            });
+    }
+
+    let TopologicalOrder {
+        mut order, cyclic, ..
+    } = topological_order(graph);
+    // the nodes of a cycle keep their order, the load then fails on them
+    order.extend(cyclic);
+
+    let mut nodes: Vec<_> = std::mem::take(&mut graph.node)
+        .into_iter()
+        .map(Some)
+        .collect();
+    graph.node = order.into_iter().filter_map(|i| nodes[i].take()).collect();
+}
+
+struct TopologicalOrder {
+    // the nodes that are not part of a cycle, each after its producers
+    order: Vec<usize>,
+    // the nodes left, on a cycle or after one, in the order of the graph
+    cyclic: Vec<usize>,
+    consumers: Vec<Vec<usize>>,
+}
+
+/// Orders the nodes of a graph with Kahn's algorithm, the ready node with
+/// the smallest outputs going first so that the order only depends on what
+/// the nodes compute. Subgraphs are left out.
+fn topological_order(graph: &tract_onnx::pb::GraphProto) -> TopologicalOrder {
+    let producers: HashMap<&str, usize> = graph
+        .node
+        .iter()
+        .enumerate()
+        .flat_map(|(i, node)| node.output.iter().map(move |output| (output.as_str(), i)))
+        .collect();
+    let mut pending_inputs = vec![0usize; graph.node.len()];
+    let mut consumers = vec![vec![]; graph.node.len()];
+    for (i, node) in graph.node.iter().enumerate() {
+        for producer in node
+            .input
+            .iter()
+            .filter_map(|input| producers.get(input.as_str()))
+        {
+            pending_inputs[i] += 1;
+            consumers[*producer].push(i);
+        }
+    }
+    let key = |i: usize| {
+        let node = &graph.node[i];
+        (&node.output, &node.op_type, &node.name, i)
+    };
+    let mut ready: std::collections::BTreeSet<_> = (0..graph.node.len())
+        .filter(|i| pending_inputs[*i] == 0)
+        .map(key)
+        .collect();
+    let mut order = Vec::with_capacity(graph.node.len());
+    while let Some((_, _, _, i)) = ready.pop_first() {
+        order.push(i);
+        for consumer in &consumers[i] {
+            pending_inputs[*consumer] -= 1;
+            if pending_inputs[*consumer] == 0 {
+                ready.insert(key(*consumer));
+            }
         }
-        Ok(outputs)
     }
+    TopologicalOrder {
+        order,
+        cyclic: (0..graph.node.len())
+            .filter(|i| pending_inputs[*i] > 0)
+            .collect(),
+        consumers,
+    }
+}
 
-    pub fn from_onnx_loaded(
-        onnx: Arc<OnnxModel>,
-        model_id: Uuid,
-        model_name: Option<String>,
-        model_hash: Digest,
-    ) -> Self {
-        InferenceModel {
-            onnx,
-            model_id,
-            model_name,
-            model_hash,
+/// Longest chain of nodes of a graph, the nodes of the subgraphs of a node
+/// counting as part of it. Fails on cycles, and as soon as the chain gets
+/// longer than `max_depth`, so that tract never walks such graphs.
+fn graph_depth(graph: &tract_onnx::pb::GraphProto, max_depth: usize) -> Result<usize> {
+    nested_depth(graph, max_depth, max_depth)
+}
+
+/// `graph_depth` of a graph that may be nested in the nodes of others,
+/// whose chains leave it `budget` nodes. The budget is checked before going
+/// down the subgraphs, so that the recursion stops with it.
+fn nested_depth(
+    graph: &tract_onnx::pb::GraphProto,
+    budget: usize,
+    max_depth: usize,
+) -> Result<usize> {
+    let too_deep = |node: &tract_onnx::pb::NodeProto| LoadFailure {
+        op: Some(node.op_type.clone()),
+        node: Some(node.name.clone()),
+        reason: format!("the graph is more than {} nodes deep", max_depth),
+    };
+    let TopologicalOrder {
+        order,
+        cyclic,
+        consumers,
+    } = topological_order(graph);
+    if let Some(&i) = cyclic.first() {
+        return Err(LoadFailure {
+            op: Some(graph.node[i].op_type.clone()),
+            node: Some(graph.node[i].name.clone()),
+            reason: "the node depends on its own outputs".to_string(),
         }
+        .into());
     }
 
-    pub fn model_name(&self) -> Option<&str> {
-        self.model_name.as_deref()
+    // the nodes are visited after all their producers
+    let mut depths = vec![0usize; graph.node.len()];
+    let mut depth = 0;
+    for i in order {
+        let node = &graph.node[i];
+        if depths[i] + 1 > budget {
+            return Err(too_deep(node).into());
+        }
+        let mut own_depth = 1;
+        for attribute in &node.attribute {
+            for subgraph in attribute.g.iter().chain(&attribute.graphs) {
+                let nested = nested_depth(subgraph, budget - depths[i] - 1, max_depth)?;
+                own_depth = own_depth.max(1 + nested);
+            }
+        }
+        depths[i] += own_depth;
+        depth = depth.max(depths[i]);
+        for consumer in &consumers[i] {
+            depths[*consumer] = depths[*consumer].max(depths[i]);
+        }
     }
+    Ok(depth)
+}
 
-    pub fn model_hash(&self) -> Digest {
-        self.model_hash
+fn load_plan(
+    model_data: &[u8],
+    optimize: bool,
+    max_graph_depth: Option<usize>,
+) -> Result<OnnxModel> {
+    read_plan(model_data, optimize, max_graph_depth)
+}
+
+// tract buffers the proto while decoding it, only the copy of the caller is
+// spared by reading from a stream
+fn read_plan(
+    mut model_data: impl Read,
+    optimize: bool,
+    max_graph_depth: Option<usize>,
+) -> Result<OnnxModel> {
+    let onnx = tract_onnx::onnx().with_ignore_output_shapes(true);
+    let proto = onnx
+        .proto_model_for_read(&mut model_data)
+        .context("Reading proto model")
+        .map_err(LoadFailure::from_tract)?;
+    if let (Some(max_depth), Some(graph)) = (max_graph_depth, &proto.graph) {
+        graph_depth(graph, max_depth)?;
+    }
+    let model_rec = onnx
+        .model_for_proto_model(&proto)
+        .context("Translating proto model to model")
+        .map_err(LoadFailure::from_tract)?;
+    // report the operators tract doesn't know before it fails on the first one
+    if let Some(node) = model_rec
+        .nodes()
+        .iter()
+        .find(|node| node.op_is::<tract_hir::ops::unimpl::UnimplementedOp>())
+    {
+        let op = node.op().name();
+        let op = op
+            .strip_prefix("Unimplemented(")
+            .and_then(|op| op.strip_suffix(')'))
+            .unwrap_or(&op);
+        return Err(LoadFailure {
+            op: Some(op.to_string()),
+            node: Some(node.name.clone()),
+            reason: "unsupported operator".to_string(),
+        }
+        .into());
+    }
+    let onnx = match optimize {
+        true => model_rec.into_optimized(),
+        false => model_rec.into_typed(),
     }
+    .map_err(LoadFailure::from_tract)?;
+    onnx.into_runnable()
+}
+
+fn output_names(onnx: &OnnxModel) -> Vec<String> {
+    onnx.outputs
+        .iter()
+        .enumerate()
+        .map(|(i, outlet)| {
+            onnx.model
+                .outlet_label(*outlet)
+                .map(|e| e.to_owned())
+                .unwrap_or_else(|| format!("output_{i}"))
+        })
+        .collect()
+}
 
-    pub fn get_output_names(&self) -> Vec<String> {
-        self.onnx
-            .outputs
+fn resolve_output_names(names: Vec<String>, mode: DuplicateOutputNames) -> Result<Vec<String>> {
+    let mut counts = HashMap::new();
+    for name in &names {
+        *counts.entry(name.as_str()).or_insert(0) += 1;
+    }
+    let mut duplicated: Vec<&str> = counts
+        .iter()
+        .filter(|(_, &count)| count > 1)
+        .map(|(&name, _)| name)
+        .collect();
+    if duplicated.is_empty() {
+        return Ok(names);
+    }
+    duplicated.sort_unstable();
+    match mode {
+        DuplicateOutputNames::Reject => bail!(
+            "Model has several outputs with the same name: {}",
+            duplicated.join(", ")
+        ),
+        DuplicateOutputNames::Suffix => Ok(names
             .iter()
             .enumerate()
-            .map(|(i, outlet)| {
-                self.onnx
-                    .model
-                    .outlet_label(*outlet)
-                    .map(|e| e.to_owned())
-                    .unwrap_or_else(|| format!("output_{i}"))
+            .map(|(i, name)| match counts[name.as_str()] {
+                1 => name.clone(),
+                _ => format!("{name}_{i}"),
             })
-            .collect()
+            .collect()),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::model_store::ModelStore;
-    use anyhow::Result;
+/// Places the inputs of a request: the named inputs go to the graph input
+/// of that name, the others fill the remaining graph inputs in order. With a
+/// seed, the seed input is bound to it and the client doesn't send it. Every
+/// graph input must then be given a tensor.
+fn input_tensors(
+    onnx: &OnnxModel,
+    inputs: &[impl Borrow<SerializedTensor>],
+    seed: Option<u64>,
+) -> Result<Vec<Tensor>> {
+    let outlets = onnx.model.input_outlets()?;
+    let input_name = |rank: usize| onnx.model.node(outlets[rank].node).name.as_str();
+    let mut slots: Vec<Option<Tensor>> = vec![None; outlets.len()];
+    if let Some(seed) = seed {
+        let (rank, fact) = seed_input(onnx)?;
+        let shape = fact.shape.as_concrete().unwrap_or(&[]);
+        slots[rank] = Some(
+            tensor0(seed)
+                .cast_to_dt(fact.datum_type)?
+                .into_owned()
+                .into_shape(shape)?,
+        );
+    }
 
-    use std::str::FromStr;
-    use std::{collections::HashMap, sync::Mutex};
+    let mut unnamed = vec![];
+    for tensor in inputs {
+        let tensor = tensor.borrow();
+        let tract_tensor = convert_datum!(create_tensor(tensor.info.datum_type.get_datum_type())(
+            &tensor.bytes_data,
+            tensor.info.fact.as_slice()
+        ))?;
+        match &tensor.info.node_name {
+            Some(node_name) => {
+                let rank = (0..outlets.len())
+                    .find(|&rank| input_name(rank) == node_name)
+                    .ok_or_else(|| anyhow!("The model has no input named {}", node_name))?;
+                if slots[rank].is_some() {
+                    match seed {
+                        Some(_) if node_name == SEED_INPUT => bail!(
+                            "The {} input is bound to the seed, it must not be sent",
+                            SEED_INPUT
+                        ),
+                        _ => bail!("Input {} is sent more than once", node_name),
+                    }
+                }
+                slots[rank] = Some(tract_tensor);
+            }
+            None => unnamed.push(tract_tensor),
+        }
+    }
+    // purely positional inputs are checked against the graph by `check_input_facts`
+    if slots.iter().all(Option::is_none) {
+        return Ok(unnamed);
+    }
+    let mut unnamed = unnamed.into_iter();
+    for slot in slots.iter_mut().filter(|slot| slot.is_none()) {
+        *slot = unnamed.next();
+    }
+    if unnamed.next().is_some() {
+        bail!("The model takes {} inputs, more were sent", outlets.len());
+    }
+    slots
+        .into_iter()
+        .enumerate()
+        .map(|(rank, slot)| slot.ok_or_else(|| anyhow!("Input {} is missing", input_name(rank))))
+        .collect()
+}
 
-    use lazy_static::lazy_static;
+/// Checks the placed inputs against the facts of the graph, so that a
+/// request that doesn't fit the graph is told apart from a failing graph.
+/// The dimensions named by the same symbol must be given the same length.
+fn check_input_facts(onnx: &OnnxModel, tensors: &[Tensor]) -> Result<()> {
+    let outlets = onnx.model.input_outlets()?;
+    if tensors.len() != outlets.len() {
+        bail!(
+            "InvalidInput: the model takes {} inputs, {} were sent",
+            outlets.len(),
+            tensors.len()
+        );
+    }
+    let mut symbols = HashMap::new();
+    for (outlet, tensor) in outlets.iter().zip(tensors) {
+        let fact = onnx.model.outlet_fact(*outlet)?;
+        let fits = fact.datum_type == tensor.datum_type()
+            && fact.rank() == tensor.rank()
+            && fact
+                .shape
+                .iter()
+                .zip(tensor.shape())
+                .all(|(dim, &len)| match dim {
+                    TDim::Val(dim) => dim as usize == len,
+                    TDim::Sym(symbol) => *symbols.entry(symbol).or_insert(len) == len,
+                    _ => true,
+                });
+        if !fits {
+            bail!(
+                "InvalidInput: input {} must be {:?}, got {:?} of shape {:?}",
+                onnx.model.node(outlet.node).name,
+                fact,
+                tensor.datum_type(),
+                tensor.shape()
+            );
+        }
+    }
+    Ok(())
+}
 
-    static MOBILENET: &[u8] = include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/tests/mobilenet/mobilenetv2-7.onnx"
-    ));
-    static GRACE_HOPPER_JPG: &[u8] = include_bytes!(concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/tests/mobilenet/grace_hopper.jpg"
-    ));
+// set by `run_graph` when a node produces a tensor over `max_tensor_size`
+fn is_tensor_size_limit(err: &anyhow::Error) -> bool {
+    err.to_string().starts_with("DynamicShapeLimitExceeded")
+}
 
-    lazy_static! {
-        static ref MODELSTORE: Mutex<ModelStore> = Mutex::new(ModelStore::new());
-    }
+// input of the stochastic models the seed of the request is bound to
+const SEED_INPUT: &str = "seed";
 
-    lazy_static! {
-        static ref UUID_HASHMAP: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+/// Rank and fact of the seed input, which must hold a single integer.
+fn seed_input(onnx: &OnnxModel) -> Result<(usize, &TypedFact)> {
+    let outlets = onnx.model.input_outlets()?;
+    let rank = outlets
+        .iter()
+        .position(|outlet| onnx.model.node(outlet.node).name == SEED_INPUT)
+        .ok_or_else(|| {
+            anyhow!(
+                "A seed is given but the model has no input named {}",
+                SEED_INPUT
+            )
+        })?;
+    let fact = onnx.model.outlet_fact(outlets[rank])?;
+    let single = fact
+        .shape
+        .as_concrete()
+        .map_or(false, |shape| shape.iter().product::<usize>() == 1);
+    if !fact.datum_type.is_integer() || !single {
+        bail!(
+            "The {} input must hold a single integer, not {:?}",
+            SEED_INPUT,
+            fact
+        );
     }
+    Ok((rank, fact))
+}
 
-    fn get_uuid(name: String) -> String {
-        let mutex_guard = UUID_HASHMAP.lock().unwrap();
-        let uuid_res = mutex_guard.get(&name);
-        match uuid_res {
-            None => "".into(),
-            Some(uuid) => uuid.into(),
-        }
+fn serialize_outputs(
+    mut result: TVec<Arc<Tensor>>,
+    output_names: &[String],
+    options: &InferenceOptions,
+) -> Result<Vec<SerializedTensor>> {
+    result = result
+        .into_iter()
+        .map(|tensor| {
+            if tensor.datum_type() == DatumType::TDim {
+                Ok(tensor.cast_to::<i64>()?.into_owned().into())
+            } else {
+                Ok(tensor)
+            }
+        })
+        .collect::<TractResult<_>>()?;
+    if let Some(datum_type) = options.output_datum_type {
+        let datum_type = datum_type.get_datum_type();
+        result = result
+            .into_iter()
+            .map(|tensor| {
+                options.check_output_cast(tensor.datum_type())?;
+                Ok(tensor.cast_to_dt(datum_type)?.into_owned().into())
+            })
+            .collect::<Result<_>>()?;
+    }
+    let mut named: Vec<(String, Arc<Tensor>)> = output_names.iter().cloned().zip(result).collect();
+    if let Some(top_k) = &options.top_k {
+        let position = named
+            .iter()
+            .position(|(name, _)| *name == top_k.output)
+            .ok_or_else(|| anyhow!("TopK: no output named {}", top_k.output))?;
+        let (values, indices) = self::top_k(&named[position].1, top_k.k)?;
+        named[position].1 = values.into();
+        named.insert(
+            position + 1,
+            (format!("{}_indices", top_k.output), indices.into()),
+        );
+    }
+    let mut outputs: Vec<SerializedTensor> = vec![];
+    for (name, tensor) in named {
+        outputs.push(SerializedTensor {
+            info: TensorInfo {
+                datum_type: ModelDatumType::try_from(tensor.datum_type())?,
+                fact: tensor.shape().to_owned(),
+                node_name: Some(name),
+                layout: None,
+            },
+            bytes_data: convert_datum!(convert_tensor(tensor.datum_type())(&tensor))?,
+        });
     }
+    Ok(outputs)
+}
 
-    fn add_uuid(name: String, uuid: String) {
-        UUID_HASHMAP.lock().unwrap().insert(name, uuid);
+// The `k` largest values of a tensor with at most one dimension larger than
+// 1, the largest first, and their indices. Equal values keep their order.
+fn top_k(tensor: &Tensor, k: usize) -> Result<(Tensor, Tensor)> {
+    if tensor.shape().iter().filter(|&&dim| dim > 1).count() > 1 {
+        bail!(
+            "TopK: output of shape {:?} has more than one axis to reduce",
+            tensor.shape()
+        );
     }
+    if tensor.len() == 0 {
+        bail!("TopK: output is empty");
+    }
+    let values = tensor.clone().into_shape(&[tensor.len()])?;
+    let keys = values.cast_to::<f64>()?;
+    let keys = keys.as_slice::<f64>()?;
+    let mut indices: Vec<usize> = (0..keys.len()).collect();
+    indices.sort_by(|&a, &b| keys[b].total_cmp(&keys[a]));
+    indices.truncate(k);
+    let top = indices
+        .iter()
+        .map(|&i| values.slice(0, i, i + 1))
+        .collect::<Result<Vec<_>>>()?;
+    let indices: Vec<i64> = indices.into_iter().map(|i| i as i64).collect();
+    Ok((Tensor::stack_tensors(0, &top)?, tensor1(&indices)))
+}
 
-    fn add_model(model_bytes: &[u8], model_name: String, optimize: bool) -> Result<(Uuid, Digest)> {
-        MODELSTORE
-            .lock()
-            .unwrap()
-            .add_model(model_bytes, Some(model_name), optimize)
+// Feeds everything hashed through `Hash` to a SHA256 digest.
+struct DigestHasher(ring::digest::Context);
+
+impl Hasher for DigestHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
     }
 
-    #[test]
-    fn load_mobilenet_optimized() {
-        let res = add_model(MOBILENET, "optimized".into(), true);
-        assert_eq!(res.is_ok(), true);
-        add_uuid("optimized".into(), res.unwrap().0.to_string())
+    fn finish(&self) -> u64 {
+        unreachable!("the digest is read with DigestHasher::digest")
     }
+}
 
-    #[test]
-    fn load_mobilenet_non_optimized() {
-        let res = add_model(MOBILENET, "non_optimized".into(), false);
-        assert_eq!(res.is_ok(), true);
-        add_uuid("non_optimized".into(), res.unwrap().0.to_string())
+impl DigestHasher {
+    fn digest(graph: &impl Hash) -> Digest {
+        let mut hasher = DigestHasher(ring::digest::Context::new(&ring::digest::SHA256));
+        graph.hash(&mut hasher);
+        hasher.0.finish()
     }
+}
 
-    #[test]
-    fn run_mobilenet_optimized() {
-        let uuid = get_uuid("optimized".into());
-        common_runmodel(uuid)
+/// How much of the intermediate tensors a trace keeps.
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDetail {
+    /// Only the shape, datum type and digest of every tensor, the memory used
+    /// by the trace does not depend on the size of the tensors.
+    Digests,
+    /// Also keep the tensors themselves.
+    Tensors,
+}
+
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone)]
+pub struct TensorTrace {
+    pub shape: Vec<usize>,
+    pub datum_type: DatumType,
+    pub digest: Digest,
+    pub tensor: Option<Arc<Tensor>>,
+}
+
+/// Outputs of one node of the graph during a traced inference.
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone)]
+pub struct NodeTrace {
+    pub node_id: usize,
+    pub name: String,
+    pub op: String,
+    pub outputs: Vec<TensorTrace>,
+}
+
+/// Time spent evaluating one node of the graph during a profiled inference.
+#[cfg(feature = "diagnostics")]
+#[derive(Debug, Clone)]
+pub struct NodeTiming {
+    pub node_id: usize,
+    pub name: String,
+    pub op: String,
+    pub elapsed: Duration,
+}
+
+/// Hashes the bytes of a model as they are read.
+struct HashingReader<R> {
+    inner: R,
+    context: ring::digest::Context,
+    len: usize,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.context.update(&buf[..read]);
+        self.len += read;
+        Ok(read)
     }
+}
 
-    #[test]
-    fn run_mobilenet_non_optimized() {
-        let uuid = get_uuid("non_optimized".into());
-        common_runmodel(uuid)
+fn encode_log_level(log_level: Option<LevelFilter>) -> usize {
+    log_level.map_or(0, |level| level as usize + 1)
+}
+
+impl InferenceModel {
+    /// Loads and compiles an ONNX model.
+    ///
+    /// Models without inputs (e.g. constant generators) are accepted and run
+    /// with an empty input list. Models without outputs are rejected since
+    /// they could never produce a response, duplicated output names are
+    /// handled as set in `options`.
+    pub fn load_model(
+        model_data: &[u8],
+        model_id: Uuid,
+        model_name: Option<String>,
+        model_hash: Digest,
+        options: &ModelOptions,
+    ) -> Result<Self> {
+        let onnx = Self::load_servable_plan(model_data, model_id, options)?;
+        Ok(
+            Self::from_onnx_loaded(onnx.into(), model_id, model_name, model_hash, options)?
+                .with_graph_size(model_data.len()),
+        )
+    }
+
+    /// Loads a model from a stream, hashing its bytes while tract reads
+    /// them, so that the caller never holds a copy of the whole model.
+    pub fn load_model_from_reader(
+        model_data: impl Read,
+        model_id: Uuid,
+        model_name: Option<String>,
+        options: &ModelOptions,
+    ) -> Result<Self> {
+        let mut model_data = HashingReader {
+            inner: model_data,
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+            len: 0,
+        };
+        let onnx = Self::load_servable_plan(&mut model_data, model_id, options)?;
+        // the hash covers the whole stream, even what the parser didn't need
+        io::copy(&mut model_data, &mut io::sink())?;
+        let model_hash = model_data.context.finish();
+        Ok(
+            Self::from_onnx_loaded(onnx.into(), model_id, model_name, model_hash, options)?
+                .with_graph_size(model_data.len),
+        )
+    }
+
+    fn load_servable_plan(
+        model_data: impl Read,
+        model_id: Uuid,
+        options: &ModelOptions,
+    ) -> Result<OnnxModel> {
+        let log_level = options.log_level;
+        model_log!(log_level, Level::Debug, "Loading model {}", model_id);
+        let onnx = read_plan(model_data, options.optimize, options.max_graph_depth)?;
+        if onnx.outputs.is_empty() {
+            bail!("Model has no outputs, it cannot produce a response");
+        }
+        model_log!(
+            log_level,
+            Level::Debug,
+            "Model {} loaded, {} nodes",
+            model_id,
+            onnx.model.nodes.len()
+        );
+        Ok(onnx)
+    }
+
+    /// Keeps a copy of the model so that a failed inference is retried
+    /// against a freshly built unoptimized graph.
+    pub fn with_fallback(self, model_data: &[u8]) -> Self {
+        let mut model = self.retain_bytes(model_data);
+        model.fallback_on_error = true;
+        model
+    }
+
+    /// Sets the approximate bytes taken by the graph, for the memory budget
+    /// of the store.
+    pub fn with_graph_size(mut self, graph_size: usize) -> Self {
+        self.graph_size = graph_size;
+        self
+    }
+
+    /// Approximate bytes taken by the graph, which may be shared with other
+    /// models, and by the graph of the A/B variant, which is not shared.
+    pub fn graph_sizes(&self) -> (usize, usize) {
+        let variant = match self.ab_variant {
+            Some(_) => self.graph_size,
+            None => 0,
+        };
+        (self.graph_size, variant)
+    }
+
+    /// The copy of the model kept for the fallback and the integrity checks.
+    pub fn retained_bytes(&self) -> Option<&[u8]> {
+        self.model_bytes.as_deref()
+    }
+
+    /// Keeps a copy of the model, which allows checking its integrity.
+    pub fn retain_bytes(mut self, model_data: &[u8]) -> Self {
+        if self.model_bytes.is_none() {
+            self.model_bytes = Some(model_data.into());
+        }
+        self
+    }
+
+    /// Hashes the retained copy of the model again and compares it with the
+    /// hash computed at upload. `None` if no copy was retained.
+    pub fn verify_integrity(&self) -> Option<bool> {
+        let model_bytes = self.model_bytes.as_ref()?;
+        let hash = ring::digest::digest(&ring::digest::SHA256, model_bytes);
+        Some(hash.as_ref() == self.model_hash.as_ref())
+    }
+
+    /// Builds an unoptimized graph next to the optimized one and serves
+    /// `fraction` of the inferences with it.
+    pub fn with_ab_variant(mut self, model_data: &[u8], fraction: f64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&fraction) {
+            bail!("The A/B fraction must be between 0 and 1, got {}", fraction);
+        }
+        if !self.options.optimize {
+            bail!("A/B comparison needs an optimized model");
+        }
+        self.ab_variant = Some(AbVariant {
+            unoptimized: load_plan(model_data, false, None)?,
+            fraction,
+            served: AtomicU64::new(0),
+        });
+        Ok(self)
+    }
+
+    /// Marks the model as degraded after `threshold` consecutive failures of
+    /// its graph, requests then fail fast for `cooldown`.
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some(CircuitBreaker::new(threshold, cooldown));
+        self
+    }
+
+    /// Aborts inferences in which a node produces a tensor bigger than
+    /// `max_tensor_size` bytes. Static limits on the inputs are not enough for
+    /// operators whose output shape depends on the input data (e.g. NonZero).
+    pub fn with_max_tensor_size(mut self, max_tensor_size: usize) -> Self {
+        self.max_tensor_size = Some(max_tensor_size);
+        self
+    }
+
+    /// Fails fast if the model is degraded.
+    pub fn check_available(&self) -> Result<()> {
+        match &self.circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker.check(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn status(&self) -> ModelStatus {
+        if self.is_draining() {
+            ModelStatus::Draining
+        } else if self.check_available().is_err() {
+            ModelStatus::Degraded
+        } else if self.warmed_up.load(Ordering::Relaxed) {
+            ModelStatus::WarmedUp
+        } else {
+            ModelStatus::Loaded
+        }
+    }
+
+    /// Counts a use of the model until the returned guard is dropped.
+    pub fn start_use(&self) -> InFlightUse<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightUse(&self.in_flight)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Makes the store refuse the new uses of the model with `Draining`, or
+    /// serve them again.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    /// Like `set_draining(true)`, tells whether the model was draining
+    /// already.
+    pub fn start_draining(&self) -> bool {
+        self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Clears the degraded state, returns false if the model has no circuit
+    /// breaker.
+    pub fn reset_circuit_breaker(&self) -> bool {
+        self.circuit_breaker
+            .as_ref()
+            .map(CircuitBreaker::reset)
+            .is_some()
+    }
+
+    /// Runs an inference and tells which graph served it.
+    pub fn run_inference(
+        &self,
+        inputs: &[impl Borrow<SerializedTensor>],
+        options: &InferenceOptions,
+    ) -> Result<(Vec<SerializedTensor>, GraphVariant)> {
+        model_log!(
+            self.log_level(),
+            Level::Debug,
+            "Running inference on model {} with {} inputs",
+            self.model_id,
+            inputs.len()
+        );
+        let (onnx, variant) = match &self.ab_variant {
+            Some(ab_variant) if ab_variant.serve_unoptimized() => {
+                (&ab_variant.unoptimized, GraphVariant::Unoptimized)
+            }
+            _ if self.options.optimize => (&*self.onnx, GraphVariant::Optimized),
+            _ => (&*self.onnx, GraphVariant::Unoptimized),
+        };
+        let tensors = input_tensors(onnx, inputs, options.seed)?;
+        // only failures of the graph itself count for the circuit breaker, not
+        // inputs that could not be decoded, don't fit the graph or make it
+        // produce too large tensors: one client must not degrade the model
+        // for the others
+        check_input_facts(onnx, &tensors)?;
+        let run = self.run_plan(onnx, variant, tensors);
+        let client_failure = matches!(&run, Err(err) if is_tensor_size_limit(err));
+        if let Some(circuit_breaker) = self.circuit_breaker.as_ref().filter(|_| !client_failure) {
+            if let Some(failures) = circuit_breaker.record(run.is_ok()) {
+                warn!(
+                    "Model {} is degraded after {} consecutive inference failures",
+                    self.model_id, failures
+                );
+            }
+        }
+        let (result, output_names, variant) = run?;
+        let outputs = serialize_outputs(result, &output_names, options)?;
+        model_log!(
+            self.log_level(),
+            Level::Trace,
+            "Inference on model {} produced {} outputs",
+            self.model_id,
+            outputs.len()
+        );
+        self.warmed_up.store(true, Ordering::Relaxed);
+        Ok((outputs, variant))
+    }
+
+    // Only the failures of the graph are retried against the unoptimized
+    // one: the inputs were checked against the graph, and inputs that make it
+    // produce too large tensors would fail there too.
+    fn run_plan(
+        &self,
+        onnx: &OnnxModel,
+        variant: GraphVariant,
+        tensors: Vec<Tensor>,
+    ) -> Result<(TVec<Arc<Tensor>>, Vec<String>, GraphVariant)> {
+        match self.run_graph(onnx, TVec::from_vec(tensors.clone())) {
+            Ok(result) => Ok((result, self.output_names(onnx)?, variant)),
+            Err(err) if is_tensor_size_limit(&err) => Err(err),
+            Err(err) => match self.fallback_plan() {
+                Some(fallback) => {
+                    warn!(
+                        "Inference on model {} failed ({}), retrying with an unoptimized graph",
+                        self.model_id, err
+                    );
+                    Ok((
+                        self.run_graph(fallback, TVec::from_vec(tensors))?,
+                        self.output_names(fallback)?,
+                        GraphVariant::Unoptimized,
+                    ))
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    fn fallback_plan(&self) -> Option<&OnnxModel> {
+        let model_bytes = self
+            .model_bytes
+            .as_ref()
+            .filter(|_| self.fallback_on_error)?;
+        self.fallback_plan
+            .get_or_init(|| match load_plan(model_bytes, false, None) {
+                Ok(fallback) => Some(fallback),
+                Err(err) => {
+                    warn!(
+                        "Model {} has no fallback, its unoptimized graph failed to load: {}",
+                        self.model_id, err
+                    );
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    fn run_graph(&self, onnx: &OnnxModel, inputs: TVec<Tensor>) -> Result<TVec<Arc<Tensor>>> {
+        let max_tensor_size = match self.max_tensor_size {
+            Some(max_tensor_size) => max_tensor_size,
+            None => return onnx.run(inputs),
+        };
+        let mut state = SimpleState::new(onnx)?;
+        state.run_plan_with_eval(inputs, |session, op_state, node, node_inputs| {
+            let outputs = tract_core::plan::eval(session, op_state, node, node_inputs)?;
+            for output in &outputs {
+                let size = output.len() * output.datum_type().size_of();
+                if size > max_tensor_size {
+                    bail!(
+                        "DynamicShapeLimitExceeded: node {} produced a tensor of {} bytes, the limit is {}",
+                        node.name,
+                        size,
+                        max_tensor_size
+                    );
+                }
+            }
+            Ok(outputs)
+        })
+    }
+
+    /// Runs an inference recording the outputs of every node, in evaluation
+    /// order, to track down where numerical issues appear in a graph.
+    ///
+    /// The trace is taken on the main graph of the model, without the A/B
+    /// variant, the fallback or the tensor size limit.
+    #[cfg(feature = "diagnostics")]
+    pub fn run_with_trace(
+        &self,
+        inputs: &[SerializedTensor],
+        detail: TraceDetail,
+    ) -> Result<(Vec<SerializedTensor>, Vec<NodeTrace>)> {
+        let tensors = input_tensors(&self.onnx, inputs, None)?;
+        let mut trace = vec![];
+        let mut state = SimpleState::new(&*self.onnx)?;
+        let result = state.run_plan_with_eval(
+            TVec::from_vec(tensors),
+            |session, op_state, node, node_inputs| {
+                let outputs = tract_core::plan::eval(session, op_state, node, node_inputs)?;
+                trace.push(NodeTrace {
+                    node_id: node.id,
+                    name: node.name.clone(),
+                    op: node.op().name().into_owned(),
+                    outputs: outputs
+                        .iter()
+                        .map(|tensor| TensorTrace {
+                            shape: tensor.shape().to_vec(),
+                            datum_type: tensor.datum_type(),
+                            digest: DigestHasher::digest(&**tensor),
+                            tensor: match detail {
+                                TraceDetail::Digests => None,
+                                TraceDetail::Tensors => Some(Arc::clone(tensor)),
+                            },
+                        })
+                        .collect(),
+                });
+                Ok::<_, anyhow::Error>(outputs)
+            },
+        )?;
+        let outputs = serialize_outputs(
+            result,
+            &self.output_names(&self.onnx)?,
+            &InferenceOptions::default(),
+        )?;
+        Ok((outputs, trace))
+    }
+
+    /// Runs an inference timing the evaluation of every node, to find the
+    /// operators the time goes to. The nodes are sorted from the slowest,
+    /// the total time of the inference comes with them.
+    ///
+    /// Like traces, profiles are taken on the main graph of the model.
+    #[cfg(feature = "diagnostics")]
+    pub fn run_with_profile(
+        &self,
+        inputs: &[SerializedTensor],
+    ) -> Result<(Vec<SerializedTensor>, Vec<NodeTiming>, Duration)> {
+        let tensors = input_tensors(&self.onnx, inputs, None)?;
+        let mut profile = vec![];
+        let mut state = SimpleState::new(&*self.onnx)?;
+        let start = Instant::now();
+        let result = state.run_plan_with_eval(
+            TVec::from_vec(tensors),
+            |session, op_state, node, node_inputs| {
+                let node_start = Instant::now();
+                let outputs = tract_core::plan::eval(session, op_state, node, node_inputs)?;
+                profile.push(NodeTiming {
+                    node_id: node.id,
+                    name: node.name.clone(),
+                    op: node.op().name().into_owned(),
+                    elapsed: node_start.elapsed(),
+                });
+                Ok::<_, anyhow::Error>(outputs)
+            },
+        )?;
+        let total = start.elapsed();
+        profile.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+        let outputs = serialize_outputs(
+            result,
+            &self.output_names(&self.onnx)?,
+            &InferenceOptions::default(),
+        )?;
+        Ok((outputs, profile, total))
+    }
+
+    pub fn from_onnx_loaded(
+        onnx: Arc<OnnxModel>,
+        model_id: Uuid,
+        model_name: Option<String>,
+        model_hash: Digest,
+        options: &ModelOptions,
+    ) -> Result<Self> {
+        // reject duplicated output names now rather than on every inference
+        resolve_output_names(output_names(&onnx), options.duplicate_output_names)?;
+        Ok(InferenceModel {
+            onnx,
+            model_id,
+            model_name,
+            model_hash,
+            options: *options,
+            log_level: AtomicUsize::new(encode_log_level(options.log_level)),
+            model_bytes: None,
+            fallback_on_error: false,
+            fallback_plan: OnceLock::new(),
+            ab_variant: None,
+            circuit_breaker: None,
+            max_tensor_size: None,
+            loaded_at: 0,
+            last_used: AtomicU64::new(0),
+            registered_at: Instant::now(),
+            warmed_up: AtomicBool::new(false),
+            graph_size: 0,
+            in_flight: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+        })
+    }
+
+    /// A new model sharing this model's graph. The unoptimized graph of an
+    /// A/B comparison is not shared, the clone only serves the main graph.
+    pub fn clone_as(
+        &self,
+        model_id: Uuid,
+        model_name: Option<String>,
+        options: Option<ModelOptions>,
+    ) -> Result<Self> {
+        let options = match options {
+            // the graph is shared, and so is its optimization
+            Some(options) => ModelOptions {
+                optimize: self.options.optimize,
+                ..options
+            },
+            None => ModelOptions {
+                log_level: self.log_level(),
+                ..self.options
+            },
+        };
+        let mut model = Self::from_onnx_loaded(
+            Arc::clone(&self.onnx),
+            model_id,
+            model_name,
+            self.model_hash,
+            &options,
+        )?;
+        model.model_bytes = self.model_bytes.clone();
+        model.fallback_on_error = self.fallback_on_error;
+        model.graph_size = self.graph_size;
+        Ok(model)
+    }
+
+    /// A new model built by applying `transform` to a copy of this model's
+    /// graph, then optimizing it again if this model is optimized. There are
+    /// no ONNX bytes for the result, its hash is computed over the graph.
+    pub fn fork(
+        &self,
+        model_id: Uuid,
+        model_name: Option<String>,
+        transform: impl FnOnce(&mut TypedModel) -> Result<()>,
+    ) -> Result<Self> {
+        let mut graph = self.onnx.model.clone();
+        transform(&mut graph)?;
+        let graph = match self.options.optimize {
+            true => graph.into_optimized()?,
+            false => graph,
+        };
+        let onnx = graph.into_runnable()?;
+        if onnx.outputs.is_empty() {
+            bail!("Model has no outputs, it cannot produce a response");
+        }
+        let model_hash = DigestHasher::digest(&onnx.model);
+        let options = ModelOptions {
+            log_level: self.log_level(),
+            ..self.options
+        };
+        Ok(
+            Self::from_onnx_loaded(onnx.into(), model_id, model_name, model_hash, &options)?
+                .with_graph_size(self.graph_size),
+        )
+    }
+
+    fn output_names(&self, onnx: &OnnxModel) -> Result<Vec<String>> {
+        resolve_output_names(output_names(onnx), self.options.duplicate_output_names)
+    }
+
+    /// Names the outputs are sent back under, in the order of the graph.
+    pub fn output_tensor_names(&self) -> Result<Vec<String>> {
+        self.output_names(&self.onnx)
+    }
+
+    /// Stamps the model with the tick at which the store registered it.
+    pub fn set_loaded_at(&mut self, tick: u64) {
+        self.loaded_at = tick;
+        *self.last_used.get_mut() = tick;
+        self.registered_at = Instant::now();
+    }
+
+    /// Time since the store registered the model.
+    pub fn age(&self) -> Duration {
+        self.registered_at.elapsed()
+    }
+
+    pub fn loaded_at(&self) -> u64 {
+        self.loaded_at
+    }
+
+    /// Records a use of the model, concurrent uses keep the latest tick.
+    pub fn touch(&self, tick: u64) {
+        self.last_used.fetch_max(tick, Ordering::Relaxed);
+    }
+
+    pub fn last_used(&self) -> u64 {
+        self.last_used.load(Ordering::Relaxed)
+    }
+
+    pub fn model_id(&self) -> Uuid {
+        self.model_id
+    }
+
+    pub fn model_name(&self) -> Option<&str> {
+        self.model_name.as_deref()
+    }
+
+    pub fn model_hash(&self) -> Digest {
+        self.model_hash
+    }
+
+    /// Log level override for the lines emitted while this model is used.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        match self.log_level.load(Ordering::Relaxed) {
+            0 => None,
+            level => LevelFilter::iter().nth(level - 1),
+        }
+    }
+
+    pub fn set_log_level(&self, log_level: Option<LevelFilter>) {
+        self.log_level
+            .store(encode_log_level(log_level), Ordering::Relaxed);
+    }
+
+    pub fn is_optimized(&self) -> bool {
+        self.options.optimize
+    }
+
+    /// Whether the uploader declared that identical inputs always give identical
+    /// outputs, which allows sharing results between requests.
+    pub fn is_deterministic(&self) -> bool {
+        self.options.deterministic
+    }
+
+    /// Rejects requests built against another schema version than the model's.
+    /// Requests that do not declare a version are always accepted.
+    pub fn check_schema_version(&self, requested: Option<u32>) -> Result<()> {
+        match requested {
+            Some(requested) if requested != self.options.schema_version => bail!(
+                "SchemaMismatch: model expects schema version {} but the request uses version {}",
+                self.options.schema_version,
+                requested
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects requests asking for outputs in a datum type they can't be
+    /// cast to.
+    pub fn check_output_cast(&self, options: &InferenceOptions) -> Result<()> {
+        for i in 0..self.onnx.model.outputs.len() {
+            let datum_type = match self.onnx.model.output_fact(i)?.datum_type {
+                // sent back as i64
+                DatumType::TDim => DatumType::I64,
+                datum_type => datum_type,
+            };
+            options.check_output_cast(datum_type)?;
+        }
+        Ok(())
+    }
+
+    /// Rejects the inputs cached for later requests that don't name an
+    /// input of the model, or don't match its fact.
+    pub fn check_named_input(&self, tensor: &SerializedTensor) -> Result<()> {
+        let node_name = match &tensor.info.node_name {
+            Some(node_name) => node_name,
+            None => bail!("A cached input must name the input of the model it is bound to"),
+        };
+        let graph = &self.onnx.model;
+        let outlet = graph
+            .input_outlets()?
+            .iter()
+            .find(|outlet| graph.node(outlet.node).name == *node_name)
+            .ok_or_else(|| anyhow!("The model has no input named {}", node_name))?;
+        let fact = graph.outlet_fact(*outlet)?;
+        let datum_type = tensor.info.datum_type.get_datum_type();
+        let shape = &tensor.info.fact;
+        let matching = fact.datum_type == datum_type
+            && fact.shape.len() == shape.len()
+            && fact
+                .shape
+                .iter()
+                .zip(shape)
+                .all(|(dim, &len)| dim.to_i64().map_or(true, |dim| dim as usize == len));
+        if !matching {
+            bail!(
+                "Input {} is {:?} {:?}, the model expects {:?}",
+                node_name,
+                datum_type,
+                shape,
+                fact
+            );
+        }
+        convert_datum!(create_tensor(datum_type)(
+            &tensor.bytes_data,
+            shape.as_slice()
+        ))?;
+        self.check_input_layouts(std::slice::from_ref(tensor))?;
+        self.check_input_values(std::slice::from_ref(tensor))
+    }
+
+    /// Rejects seeds for models without a seed input.
+    pub fn check_seed(&self, options: &InferenceOptions) -> Result<()> {
+        match options.seed {
+            Some(_) => seed_input(&self.onnx).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Rejects top-k reductions of unknown outputs, or of outputs with more
+    /// than one axis to reduce.
+    pub fn check_top_k(&self, options: &InferenceOptions) -> Result<()> {
+        let top_k = match &options.top_k {
+            Some(top_k) => top_k,
+            None => return Ok(()),
+        };
+        if top_k.k == 0 {
+            bail!("TopK: k must be at least 1");
+        }
+        let position = self
+            .output_names(&self.onnx)?
+            .iter()
+            .position(|name| *name == top_k.output)
+            .ok_or_else(|| anyhow!("TopK: no output named {}", top_k.output))?;
+        let fact = self.onnx.model.output_fact(position)?;
+        // only the dimensions known to be larger than 1 can be rejected now
+        let axes = fact
+            .shape
+            .iter()
+            .filter(|dim| dim.to_i64().map_or(false, |dim| dim > 1))
+            .count();
+        if axes > 1 {
+            bail!(
+                "TopK: output {} has more than one axis to reduce",
+                top_k.output
+            );
+        }
+        Ok(())
+    }
+
+    /// Rejects numeric inputs with values outside the model's input range.
+    /// NaN values are always out of range.
+    pub fn check_input_values(&self, inputs: &[impl Borrow<SerializedTensor>]) -> Result<()> {
+        let range = match self.options.input_range {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+        for (i, tensor) in inputs.iter().enumerate() {
+            let tensor = tensor.borrow();
+            let values = match values_as_f64(tensor)? {
+                Some(values) => values,
+                None => continue,
+            };
+            if let Some((index, value)) = values
+                .into_iter()
+                .enumerate()
+                .find(|(_, value)| !(range.min..=range.max).contains(value))
+            {
+                bail!(
+                    "InputOutOfRange: input {} has value {} at index {}, outside of [{}, {}]",
+                    tensor
+                        .info
+                        .node_name
+                        .clone()
+                        .unwrap_or_else(|| format!("#{i}")),
+                    value,
+                    index,
+                    range.min,
+                    range.max
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects inputs declaring another layout than the model's. Inputs
+    /// without a declared layout are not checked.
+    pub fn check_input_layouts(&self, inputs: &[impl Borrow<SerializedTensor>]) -> Result<()> {
+        let expected = match self.options.input_layout {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        for tensor in inputs {
+            let tensor = tensor.borrow();
+            match tensor.info.layout {
+                Some(layout) if layout != expected => bail!(
+                    "LayoutMismatch: model expects {} inputs but input {} is {}",
+                    expected,
+                    tensor.info.node_name.as_deref().unwrap_or("(unnamed)"),
+                    layout
+                ),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the model on each set of inputs of `corpus`, or on zero-filled
+    /// inputs if it is empty, so that the first request does not pay for
+    /// the first run of the graph. Representative inputs go through the
+    /// same paths as the real ones, which zeros may not. The corpus is
+    /// checked like the inputs of a request.
+    pub fn warm_up(&self, corpus: &[Vec<SerializedTensor>]) -> Result<()> {
+        let zeros;
+        let corpus = match corpus.is_empty() {
+            true => {
+                zeros = [self.zero_inputs()?];
+                &zeros[..]
+            }
+            false => corpus,
+        };
+        let options = InferenceOptions::default();
+        self.check_seed(&options)?;
+        for inputs in corpus {
+            self.check_input_layouts(inputs)?;
+            self.check_input_values(inputs)?;
+            self.run_inference(inputs, &options)?;
+        }
+        model_log!(
+            self.log_level(),
+            Level::Debug,
+            "Model {} warmed up with {} inferences",
+            self.model_id,
+            corpus.len()
+        );
+        Ok(())
+    }
+
+    /// Builds zero-filled inputs matching the model's input facts.
+    /// Symbolic dimensions are set to 1.
+    pub fn zero_inputs(&self) -> Result<Vec<SerializedTensor>> {
+        let mut inputs = vec![];
+        for i in 0..self.onnx.model.inputs.len() {
+            let fact = self.onnx.model.input_fact(i)?;
+            let shape: Vec<usize> = fact
+                .shape
+                .iter()
+                .map(|dim| dim.to_i64().map_or(1, |dim| dim as usize))
+                .collect();
+            let len = shape.iter().product::<usize>() * fact.datum_type.size_of();
+            inputs.push(SerializedTensor {
+                info: TensorInfo {
+                    fact: shape,
+                    datum_type: ModelDatumType::try_from(fact.datum_type)?,
+                    node_name: None,
+                    layout: None,
+                },
+                bytes_data: vec![0; len],
+            });
+        }
+        Ok(inputs)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_graphs {
+    //! Tiny ONNX graphs built by hand, for tests that need a specific graph shape.
+    use prost::Message;
+    use tract_onnx::pb::*;
+
+    pub const FLOAT: i32 = tensor_proto::DataType::Float as i32;
+    pub const INT64: i32 = tensor_proto::DataType::Int64 as i32;
+    pub const FLOAT16: i32 = tensor_proto::DataType::Float16 as i32;
+
+    pub fn value_info(name: &str, elem_type: i32, dims: &[i64]) -> ValueInfoProto {
+        let dim = dims
+            .iter()
+            .map(|&dim| tensor_shape_proto::Dimension {
+                value: Some(tensor_shape_proto::dimension::Value::DimValue(dim)),
+                ..Default::default()
+            })
+            .collect();
+        ValueInfoProto {
+            name: name.to_string(),
+            r#type: Some(TypeProto {
+                value: Some(type_proto::Value::TensorType(type_proto::Tensor {
+                    elem_type,
+                    shape: Some(TensorShapeProto { dim }),
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// A tensor whose dimensions are only named.
+    pub fn symbolic_value_info(name: &str, elem_type: i32, dims: &[&str]) -> ValueInfoProto {
+        let mut value_info = value_info(name, elem_type, &[]);
+        if let Some(type_proto::Value::TensorType(tensor)) = value_info
+            .r#type
+            .as_mut()
+            .and_then(|type_proto| type_proto.value.as_mut())
+        {
+            tensor.shape = Some(TensorShapeProto {
+                dim: dims
+                    .iter()
+                    .map(|dim| tensor_shape_proto::Dimension {
+                        value: Some(tensor_shape_proto::dimension::Value::DimParam(
+                            dim.to_string(),
+                        )),
+                        ..Default::default()
+                    })
+                    .collect(),
+            });
+        }
+        value_info
+    }
+
+    /// A tensor of unknown rank.
+    pub fn unshaped_value_info(name: &str, elem_type: i32) -> ValueInfoProto {
+        let mut value_info = value_info(name, elem_type, &[]);
+        if let Some(type_proto::Value::TensorType(tensor)) = value_info
+            .r#type
+            .as_mut()
+            .and_then(|type_proto| type_proto.value.as_mut())
+        {
+            tensor.shape = None;
+        }
+        value_info
+    }
+
+    pub fn node(op_type: &str, inputs: &[&str], outputs: &[&str]) -> NodeProto {
+        NodeProto {
+            input: inputs.iter().map(|s| s.to_string()).collect(),
+            output: outputs.iter().map(|s| s.to_string()).collect(),
+            name: outputs.first().unwrap_or(&op_type).to_string(),
+            op_type: op_type.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn cast(input: &str, output: &str, to: i32) -> NodeProto {
+        let mut node = node("Cast", &[input], &[output]);
+        node.attribute.push(AttributeProto {
+            name: "to".to_string(),
+            r#type: attribute_proto::AttributeType::Int as i32,
+            i: to as i64,
+            ..Default::default()
+        });
+        node
+    }
+
+    pub fn constant(output: &str, values: &[f32]) -> NodeProto {
+        let mut node = node("Constant", &[], &[output]);
+        node.attribute.push(AttributeProto {
+            name: "value".to_string(),
+            r#type: attribute_proto::AttributeType::Tensor as i32,
+            t: Some(TensorProto {
+                dims: vec![values.len() as i64],
+                data_type: FLOAT,
+                float_data: values.to_vec(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        node
+    }
+
+    pub fn model(
+        nodes: Vec<NodeProto>,
+        inputs: Vec<ValueInfoProto>,
+        outputs: Vec<ValueInfoProto>,
+    ) -> Vec<u8> {
+        ModelProto {
+            ir_version: 7,
+            opset_import: vec![OperatorSetIdProto {
+                domain: String::new(),
+                version: 13,
+            }],
+            graph: Some(GraphProto {
+                node: nodes,
+                name: "test_graph".to_string(),
+                input: inputs,
+                output: outputs,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_graphs::*;
+    use super::{InferenceModel, *};
+    use crate::model_store::{ModelStore, ModelStoreError};
+    use anyhow::Result;
+
+    use std::str::FromStr;
+    use std::{collections::HashMap, sync::Mutex};
+
+    use lazy_static::lazy_static;
+
+    static MOBILENET: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/mobilenet/mobilenetv2-7.onnx"
+    ));
+    static GRACE_HOPPER_JPG: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/mobilenet/grace_hopper.jpg"
+    ));
+
+    const OPTIMIZED: ModelOptions = ModelOptions {
+        optimize: true,
+        schema_version: 0,
+        deterministic: false,
+        log_level: None,
+        ab_unoptimized_fraction: None,
+        input_layout: None,
+        duplicate_output_names: DuplicateOutputNames::Suffix,
+        input_range: None,
+        max_graph_depth: None,
+    };
+
+    lazy_static! {
+        static ref MODELSTORE: Mutex<ModelStore> = Mutex::new(ModelStore::new());
+    }
+
+    lazy_static! {
+        static ref UUID_HASHMAP: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    }
+
+    fn get_uuid(name: String) -> String {
+        let mutex_guard = UUID_HASHMAP.lock().unwrap();
+        let uuid_res = mutex_guard.get(&name);
+        match uuid_res {
+            None => "".into(),
+            Some(uuid) => uuid.into(),
+        }
+    }
+
+    fn add_uuid(name: String, uuid: String) {
+        UUID_HASHMAP.lock().unwrap().insert(name, uuid);
+    }
+
+    fn add_model(
+        model_bytes: &[u8],
+        model_name: String,
+        optimize: bool,
+    ) -> Result<(Uuid, Digest), ModelStoreError> {
+        MODELSTORE.lock().unwrap().add_model(
+            model_bytes,
+            Some(model_name),
+            ModelOptions {
+                optimize,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn load_mobilenet_optimized() {
+        let res = add_model(MOBILENET, "optimized".into(), true);
+        assert_eq!(res.is_ok(), true);
+        add_uuid("optimized".into(), res.unwrap().0.to_string())
+    }
+
+    #[test]
+    fn load_mobilenet_non_optimized() {
+        let res = add_model(MOBILENET, "non_optimized".into(), false);
+        assert_eq!(res.is_ok(), true);
+        add_uuid("non_optimized".into(), res.unwrap().0.to_string())
+    }
+
+    #[test]
+    fn run_mobilenet_optimized() {
+        let uuid = get_uuid("optimized".into());
+        common_runmodel(uuid)
+    }
+
+    #[test]
+    fn run_mobilenet_non_optimized() {
+        let uuid = get_uuid("non_optimized".into());
+        common_runmodel(uuid)
+    }
+
+    #[test]
+    fn run_model_without_inputs() {
+        let onnx = model(
+            vec![constant("values", &[1.0, 2.0, 3.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[3])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+
+        let (outputs, _) = model
+            .run_inference(&[] as &[SerializedTensor], &InferenceOptions::default())
+            .unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].info.fact, vec![3]);
+        assert_eq!(
+            Vec::<f32>::from_le_bytes(&outputs[0].bytes_data).unwrap(),
+            vec![1.0, 2.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn reject_model_without_outputs() {
+        let onnx = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let err = InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Model has no outputs, it cannot produce a response"
+        );
+    }
+
+    #[test]
+    fn reject_mismatched_schema_version() {
+        let onnx = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model = InferenceModel::load_model(
+            &onnx,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions {
+                schema_version: 2,
+                ..OPTIMIZED
+            },
+        )
+        .unwrap();
+
+        assert!(model.check_schema_version(None).is_ok());
+        assert!(model.check_schema_version(Some(2)).is_ok());
+        let err = model.check_schema_version(Some(1)).unwrap_err();
+        assert!(err.to_string().starts_with("SchemaMismatch"));
+    }
+
+    #[test]
+    fn per_model_log_level() {
+        let onnx = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let verbose = InferenceModel::load_model(
+            &onnx,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions {
+                log_level: Some(LevelFilter::Debug),
+                ..OPTIMIZED
+            },
+        )
+        .unwrap();
+        let quiet =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+
+        let target =
+            |model: &InferenceModel, level| model_log_target(model.log_level(), level, "default");
+        assert_eq!(target(&verbose, Level::Debug), Some(MODEL_LOG_TARGET));
+        assert_eq!(target(&verbose, Level::Trace), None);
+        // models without an override follow the global filter
+        assert_eq!(target(&quiet, Level::Debug), Some("default"));
+
+        verbose.set_log_level(Some(LevelFilter::Trace));
+        assert_eq!(verbose.log_level(), Some(LevelFilter::Trace));
+        assert_eq!(target(&verbose, Level::Trace), Some(MODEL_LOG_TARGET));
+        assert_eq!(quiet.log_level(), None);
+    }
+
+    #[test]
+    fn codec_handles_all_datum_types() {
+        fn roundtrip(datum_type: ModelDatumType) -> Result<Vec<u8>> {
+            let dt = datum_type.get_datum_type();
+            let bytes = vec![1u8; 2 * dt.size_of()];
+            let tensor = convert_datum!(create_tensor(dt)(&bytes, &[2]))?;
+            convert_datum!(convert_tensor(dt)(&tensor))
+        }
+
+        let datum_types = ModelDatumType::all();
+        assert_eq!(datum_types.len(), 11);
+        for datum_type in datum_types {
+            let dt = datum_type.get_datum_type();
+            assert_eq!(ModelDatumType::try_from(dt).unwrap(), datum_type);
+            assert_eq!(roundtrip(datum_type).unwrap(), vec![1u8; 2 * dt.size_of()]);
+        }
+    }
+
+    // inputs of the graphs gathering `data` at `indices`
+    fn gather_inputs(data: &[f32], index: i64) -> Vec<SerializedTensor> {
+        vec![
+            SerializedTensor {
+                info: TensorInfo {
+                    fact: vec![data.len()],
+                    datum_type: ModelDatumType::F32,
+                    node_name: None,
+                    layout: None,
+                },
+                bytes_data: data.to_le_bytes(),
+            },
+            SerializedTensor {
+                info: TensorInfo {
+                    fact: vec![1],
+                    datum_type: ModelDatumType::I64,
+                    node_name: None,
+                    layout: None,
+                },
+                bytes_data: index.to_le_bytes().to_vec(),
+            },
+        ]
+    }
+
+    // fails inside tract when the index is out of bounds, the inputs fit
+    fn gather(len: i64) -> Vec<u8> {
+        model(
+            vec![node("Gather", &["data", "indices"], &["gathered"])],
+            vec![
+                value_info("data", FLOAT, &[len]),
+                value_info("indices", INT64, &[1]),
+            ],
+            vec![value_info("gathered", FLOAT, &[1])],
+        )
+    }
+
+    #[test]
+    fn fallback_on_inference_error() {
+        // the "optimized" graph fails on the index of the request
+        let broken = gather(3);
+        let working = model(
+            vec![node("Relu", &["data"], &["relu"])],
+            vec![
+                value_info("data", FLOAT, &[3]),
+                value_info("indices", INT64, &[1]),
+            ],
+            vec![value_info("relu", FLOAT, &[3])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &working);
+        let broken =
+            InferenceModel::load_model(&broken, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        let inputs = gather_inputs(&[-1.0, 0.5, 2.0], 5);
+        assert!(broken
+            .run_inference(&inputs, &InferenceOptions::default())
+            .is_err());
+
+        let model = InferenceModel::from_onnx_loaded(
+            Arc::clone(&broken.onnx),
+            Uuid::new_v4(),
+            None,
+            digest,
+            &OPTIMIZED,
+        )
+        .unwrap()
+        .with_fallback(&working);
+        // inputs that don't fit the graph are not retried
+        assert!(model
+            .run_inference(&inputs[..1], &InferenceOptions::default())
+            .is_err());
+        assert!(model.fallback_plan.get().is_none());
+
+        for _ in 0..2 {
+            let (outputs, variant) = model
+                .run_inference(&inputs, &InferenceOptions::default())
+                .unwrap();
+            assert_eq!(variant, GraphVariant::Unoptimized);
+            assert_eq!(outputs[0].info.node_name.as_deref(), Some("relu"));
+            assert_eq!(
+                Vec::<f32>::from_le_bytes(&outputs[0].bytes_data).unwrap(),
+                vec![0.0, 0.5, 2.0]
+            );
+        }
+        // the unoptimized graph was built once, by the first retry
+        let fallback = model.fallback_plan.get().unwrap().as_ref().unwrap();
+        assert!(std::ptr::eq(fallback, model.fallback_plan().unwrap()));
+    }
+
+    #[test]
+    fn ab_split_between_graph_variants() {
+        let onnx = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("relu", FLOAT, &[3])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model = InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED)
+            .unwrap()
+            .with_ab_variant(&onnx, 0.25)
+            .unwrap();
+        let input = SerializedTensor {
+            info: TensorInfo {
+                fact: vec![3],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [-1.0f32, 0.5, 2.0].as_ref().to_le_bytes(),
+        };
+
+        let mut unoptimized = 0;
+        for _ in 0..400 {
+            let (outputs, variant) = model
+                .run_inference(&[input.clone()], &InferenceOptions::default())
+                .unwrap();
+            assert_eq!(
+                Vec::<f32>::from_le_bytes(&outputs[0].bytes_data).unwrap(),
+                vec![0.0, 0.5, 2.0]
+            );
+            if variant == GraphVariant::Unoptimized {
+                unoptimized += 1;
+            }
+        }
+        assert!((90..=110).contains(&unoptimized), "{}", unoptimized);
+
+        let unoptimized_model = InferenceModel::load_model(
+            &onnx,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions::default(),
+        )
+        .unwrap();
+        assert!(unoptimized_model.with_ab_variant(&onnx, 0.5).is_err());
+    }
+
+    #[test]
+    fn reject_mismatched_input_layout() {
+        let onnx = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[1, 3, 2, 2])],
+            vec![value_info("relu", FLOAT, &[1, 3, 2, 2])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model = InferenceModel::load_model(
+            &onnx,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions {
+                input_layout: Some(TensorLayout::Nchw),
+                ..OPTIMIZED
+            },
+        )
+        .unwrap();
+        let input = |fact: Vec<usize>, layout| SerializedTensor {
+            info: TensorInfo {
+                fact,
+                datum_type: ModelDatumType::F32,
+                node_name: Some("input".to_string()),
+                layout,
+            },
+            bytes_data: [1.0f32; 12].as_ref().to_le_bytes(),
+        };
+
+        let err = model
+            .check_input_layouts(&[input(vec![1, 2, 2, 3], Some(TensorLayout::Nhwc))])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "LayoutMismatch: model expects NCHW inputs but input input is NHWC"
+        );
+        let nchw = [input(vec![1, 3, 2, 2], Some(TensorLayout::Nchw))];
+        assert!(model.check_input_layouts(&nchw).is_ok());
+        assert!(model
+            .run_inference(&nchw, &InferenceOptions::default())
+            .is_ok());
+        // inputs without a declared layout are not checked
+        assert!(model
+            .check_input_layouts(&[input(vec![1, 3, 2, 2], None)])
+            .is_ok());
+    }
+
+    #[test]
+    fn duplicate_output_names() {
+        let onnx = model(
+            vec![
+                node("Relu", &["input"], &["relu"]),
+                node("Neg", &["input"], &["neg"]),
+            ],
+            vec![value_info("input", FLOAT, &[1])],
+            vec![
+                value_info("relu", FLOAT, &[1]),
+                value_info("neg", FLOAT, &[1]),
+                value_info("relu", FLOAT, &[1]),
+            ],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let err = InferenceModel::load_model(
+            &onnx,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions {
+                duplicate_output_names: DuplicateOutputNames::Reject,
+                ..OPTIMIZED
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Model has several outputs with the same name: relu"
+        );
+
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        let input = SerializedTensor {
+            info: TensorInfo {
+                fact: vec![1],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [1.0f32].as_ref().to_le_bytes(),
+        };
+        let (outputs, _) = model
+            .run_inference(&[input], &InferenceOptions::default())
+            .unwrap();
+        let names: Vec<_> = outputs
+            .iter()
+            .map(|output| output.info.node_name.as_deref().unwrap())
+            .collect();
+        assert_eq!(names, vec!["relu_0", "neg", "relu_2"]);
+    }
+
+    #[test]
+    fn dynamic_model_with_request_facts() {
+        // without a rank, tract cannot type the graph
+        let unranked = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![unshaped_value_info("input", FLOAT)],
+            vec![unshaped_value_info("relu", FLOAT)],
+        );
+        let err = load_plan(&unranked, true, None).unwrap_err();
+        let failure = err.downcast_ref::<LoadFailure>().unwrap();
+        assert_eq!(failure.node.as_deref(), Some("input"));
+
+        // the graph does not declare the dimensions of its tensors
+        let dynamic = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![symbolic_value_info("input", FLOAT, &["n", "m"])],
+            vec![symbolic_value_info("relu", FLOAT, &["n", "m"])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &dynamic);
+        let model =
+            InferenceModel::load_model(&dynamic, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        for fact in [vec![3, 1], vec![2, 2]] {
+            let values: Vec<f32> = (0..fact.iter().product::<usize>())
+                .map(|i| i as f32 - 1.0)
+                .collect();
+            let input = SerializedTensor {
+                info: TensorInfo {
+                    fact: fact.clone(),
+                    datum_type: ModelDatumType::F32,
+                    node_name: None,
+                    layout: None,
+                },
+                bytes_data: values.as_slice().to_le_bytes(),
+            };
+            let (outputs, _) = model
+                .run_inference(&[input], &InferenceOptions::default())
+                .unwrap();
+            assert_eq!(outputs[0].info.fact, fact);
+        }
+    }
+
+    #[test]
+    fn warm_up_with_a_corpus() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[4])],
+            vec![value_info("relu", FLOAT, &[4])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &graph);
+        let input = |values: [f32; 4]| SerializedTensor {
+            info: TensorInfo {
+                fact: vec![4],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: values.as_ref().to_le_bytes(),
+        };
+        let load = || {
+            InferenceModel::load_model(&graph, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap()
+        };
+
+        let model = load();
+        model
+            .warm_up(&[vec![input([-1.0, 2.0, -3.0, 4.0])]])
+            .unwrap();
+        assert_eq!(model.status(), ModelStatus::WarmedUp);
+        let (outputs, _) = model
+            .run_inference(
+                &[input([1.0, -2.0, 3.0, -4.0])],
+                &InferenceOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            outputs[0].bytes_data,
+            [1.0f32, 0.0, 3.0, 0.0].as_ref().to_le_bytes()
+        );
+
+        // no corpus, zeros
+        let model = load();
+        model.warm_up(&[]).unwrap();
+        assert_eq!(model.status(), ModelStatus::WarmedUp);
+        // a corpus that does not fit the model
+        assert!(load().warm_up(&[vec![]]).is_err());
+        // nor its input range
+        let model = InferenceModel::load_model(
+            &graph,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions {
+                input_range: Some(ValueRange { min: 0.0, max: 1.0 }),
+                ..OPTIMIZED
+            },
+        )
+        .unwrap();
+        assert!(model.warm_up(&[vec![input([0.0, 2.0, 0.0, 0.0])]]).is_err());
+        assert_eq!(model.status(), ModelStatus::Loaded);
+    }
+
+    #[test]
+    fn circuit_breaker_trips_on_repeated_failures() {
+        let broken = gather(1);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &broken);
+        let model = InferenceModel::load_model(&broken, Uuid::new_v4(), None, digest, &OPTIMIZED)
+            .unwrap()
+            .with_circuit_breaker(3, Duration::from_secs(3600));
+        let inputs = gather_inputs(&[1.0], 5);
+
+        // inputs that don't fit the graph never trip it
+        for _ in 0..5 {
+            assert!(model
+                .run_inference(&inputs[..1], &InferenceOptions::default())
+                .is_err());
+        }
+        for _ in 0..3 {
+            assert!(model.check_available().is_ok());
+            assert!(model
+                .run_inference(&inputs, &InferenceOptions::default())
+                .is_err());
+        }
+        let err = model.check_available().unwrap_err();
+        assert!(err.to_string().starts_with("Degraded"));
+
+        assert!(model.reset_circuit_breaker());
+        assert!(model.check_available().is_ok());
+    }
+
+    #[test]
+    fn limit_data_dependent_tensor_sizes() {
+        let onnx = model(
+            vec![node("NonZero", &["input"], &["indices"])],
+            vec![value_info("input", FLOAT, &[8])],
+            vec![value_info("indices", INT64, &[1, 8])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model = InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED)
+            .unwrap()
+            .with_max_tensor_size(40);
+        let input = |values: [f32; 8]| SerializedTensor {
+            info: TensorInfo {
+                fact: vec![8],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: values.as_ref().to_le_bytes(),
+        };
+
+        // one non zero value: a single index
+        let (outputs, _) = model
+            .run_inference(
+                &[input([0.0, 0.0, 3.0, 0.0, 0.0, 0.0, 0.0, 0.0])],
+                &InferenceOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(outputs[0].info.fact, vec![1, 1]);
+
+        // same input size, but eight indices of 8 bytes each
+        let err = model
+            .run_inference(&[input([1.0; 8])], &InferenceOptions::default())
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("DynamicShapeLimitExceeded"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn load_failures_point_at_the_node() {
+        let unsupported = model(
+            vec![
+                node("Relu", &["input"], &["relu"]),
+                node("FancyOp", &["relu"], &["fancy"]),
+            ],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("fancy", FLOAT, &[3])],
+        );
+        let err = load_plan(&unsupported, true, None).unwrap_err();
+        let failure = err.downcast_ref::<LoadFailure>().unwrap();
+        assert_eq!(failure.op.as_deref(), Some("FancyOp"));
+        assert_eq!(failure.node.as_deref(), Some("fancy"));
+        assert_eq!(
+            err.to_string(),
+            "LoadFailure: node fancy (FancyOp) unsupported operator"
+        );
+
+        let mismatched = model(
+            vec![node("Add", &["a", "b"], &["sum"])],
+            vec![value_info("a", FLOAT, &[3]), value_info("b", FLOAT, &[4])],
+            vec![value_info("sum", FLOAT, &[3])],
+        );
+        let err = load_plan(&mismatched, false, None).unwrap_err();
+        let failure = err.downcast_ref::<LoadFailure>().unwrap();
+        assert_eq!(failure.op.as_deref(), Some("Add"));
+        assert_eq!(failure.node.as_deref(), Some("sum"));
+        assert!(failure.reason.contains("broadcast"), "{}", failure.reason);
+
+        let err = load_plan(b"not a model", true, None).unwrap_err();
+        let failure = err.downcast_ref::<LoadFailure>().unwrap();
+        assert_eq!(failure.node, None);
+    }
+
+    #[test]
+    fn reject_deep_graphs() {
+        let names: Vec<_> = (0..=64).map(|i| format!("relu_{i}")).collect();
+        let deep = model(
+            (1..names.len())
+                .map(|i| node("Relu", &[&names[i - 1]], &[&names[i]]))
+                .collect(),
+            vec![value_info(&names[0], FLOAT, &[2])],
+            vec![value_info(names.last().unwrap(), FLOAT, &[2])],
+        );
+        assert!(load_plan(&deep, false, Some(64)).is_ok());
+        let err = load_plan(&deep, false, Some(63)).unwrap_err();
+        let failure = err.downcast_ref::<LoadFailure>().unwrap();
+        assert_eq!(failure.node.as_deref(), Some("relu_64"));
+
+        let cyclic = model(
+            vec![
+                node("Add", &["input", "second"], &["first"]),
+                node("Relu", &["first"], &["second"]),
+            ],
+            vec![value_info("input", FLOAT, &[2])],
+            vec![value_info("second", FLOAT, &[2])],
+        );
+        let err = load_plan(&cyclic, false, Some(64)).unwrap_err();
+        assert!(
+            err.to_string().contains("depends on its own outputs"),
+            "{}",
+            err
+        );
+
+        // the nesting of subgraphs stops at the limit, not at the innermost one
+        let mut nested = tract_onnx::pb::GraphProto::default();
+        for i in (0..1000).rev() {
+            let mut branch = node("If", &["cond"], &[&format!("if_{i}")]);
+            branch.attribute.push(tract_onnx::pb::AttributeProto {
+                name: "then_branch".to_string(),
+                g: Some(nested),
+                ..Default::default()
+            });
+            nested = tract_onnx::pb::GraphProto {
+                node: vec![branch],
+                ..Default::default()
+            };
+        }
+        assert_eq!(graph_depth(&nested, 1000).unwrap(), 1000);
+        let err = graph_depth(&nested, 8).unwrap_err();
+        let failure = err.downcast_ref::<LoadFailure>().unwrap();
+        assert_eq!(failure.node.as_deref(), Some("if_8"));
+        assert!(failure.reason.contains("more than 8 nodes"), "{}", err);
+    }
+
+    #[test]
+    fn detect_corrupted_retained_bytes() {
+        let onnx = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        assert_eq!(model.verify_integrity(), None);
+
+        let mut model = model.retain_bytes(&onnx);
+        assert_eq!(model.verify_integrity(), Some(true));
+
+        // flip a bit of the retained copy in place
+        Arc::get_mut(model.model_bytes.as_mut().unwrap()).unwrap()[0] ^= 1;
+        assert_eq!(model.verify_integrity(), Some(false));
+    }
+
+    #[test]
+    fn cast_outputs_to_requested_datum_type() {
+        let onnx = model(
+            vec![cast("input", "half", FLOAT16)],
+            vec![value_info("input", FLOAT, &[2])],
+            vec![value_info("half", FLOAT16, &[2])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        let input = [SerializedTensor {
+            info: TensorInfo {
+                fact: vec![2],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [1.5f32, -2.0].as_ref().to_le_bytes(),
+        }];
+
+        // float16 can't be sent back as is
+        assert!(model
+            .run_inference(&input, &InferenceOptions::default())
+            .is_err());
+
+        let as_f32 = InferenceOptions {
+            output_datum_type: Some(ModelDatumType::F32),
+            ..Default::default()
+        };
+        assert!(model.check_output_cast(&as_f32).is_ok());
+        let (outputs, _) = model.run_inference(&input, &as_f32).unwrap();
+        assert_eq!(outputs[0].info.datum_type, ModelDatumType::F32);
+        assert_eq!(
+            Vec::<f32>::from_le_bytes(&outputs[0].bytes_data).unwrap(),
+            vec![1.5, -2.0]
+        );
+
+        let as_i32 = InferenceOptions {
+            output_datum_type: Some(ModelDatumType::I32),
+            ..Default::default()
+        };
+        assert!(model.check_output_cast(&as_i32).is_err());
+        assert!(model.run_inference(&input, &as_i32).is_err());
+        let lossy = InferenceOptions {
+            allow_lossy_cast: true,
+            ..as_i32
+        };
+        let (outputs, _) = model.run_inference(&input, &lossy).unwrap();
+        assert_eq!(
+            Vec::<i32>::from_le_bytes(&outputs[0].bytes_data).unwrap(),
+            vec![1, -2]
+        );
+    }
+
+    #[test]
+    fn reject_inputs_out_of_range() {
+        let onnx = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("relu", FLOAT, &[3])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model = InferenceModel::load_model(
+            &onnx,
+            Uuid::new_v4(),
+            None,
+            digest,
+            &ModelOptions {
+                input_range: Some(ValueRange { min: 0.0, max: 1.0 }),
+                ..OPTIMIZED
+            },
+        )
+        .unwrap();
+        let input = |values: [f32; 3]| SerializedTensor {
+            info: TensorInfo {
+                fact: vec![3],
+                datum_type: ModelDatumType::F32,
+                node_name: Some("input".to_string()),
+                layout: None,
+            },
+            bytes_data: values.as_ref().to_le_bytes(),
+        };
+
+        assert!(model.check_input_values(&[input([0.0, 0.5, 1.0])]).is_ok());
+        let err = model
+            .check_input_values(&[input([0.0, 255.0, 1.0])])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "InputOutOfRange: input input has value 255 at index 1, outside of [0, 1]"
+        );
+        assert!(model
+            .check_input_values(&[input([f32::NAN, 0.5, 1.0])])
+            .is_err());
+    }
+
+    #[test]
+    fn top_k_of_a_classifier() {
+        let onnx = model(
+            vec![node("Relu", &["input"], &["scores"])],
+            vec![value_info("input", FLOAT, &[1, 8])],
+            vec![value_info("scores", FLOAT, &[1, 8])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        let input = [SerializedTensor {
+            info: TensorInfo {
+                fact: vec![1, 8],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [0.1f32, 0.7, -1.0, 0.3, 0.9, 0.3, 0.05, 0.2]
+                .as_ref()
+                .to_le_bytes(),
+        }];
+        let options = InferenceOptions {
+            top_k: Some(TopK {
+                output: "scores".to_string(),
+                k: 5,
+            }),
+            ..Default::default()
+        };
+        assert!(model.check_top_k(&options).is_ok());
+        let (outputs, _) = model.run_inference(&input, &options).unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].info.fact, vec![5]);
+        assert_eq!(
+            Vec::<f32>::from_le_bytes(&outputs[0].bytes_data).unwrap(),
+            vec![0.9, 0.7, 0.3, 0.3, 0.2]
+        );
+        assert_eq!(outputs[1].info.node_name.as_deref(), Some("scores_indices"));
+        assert_eq!(
+            Vec::<i64>::from_le_bytes(&outputs[1].bytes_data).unwrap(),
+            vec![4, 1, 3, 5, 7]
+        );
+
+        let unknown = InferenceOptions {
+            top_k: Some(TopK {
+                output: "logits".to_string(),
+                k: 5,
+            }),
+            ..Default::default()
+        };
+        assert!(model.check_top_k(&unknown).is_err());
+    }
+
+    #[test]
+    fn inputs_addressed_by_name() {
+        let onnx = model(
+            vec![node("Sub", &["minuend", "subtrahend"], &["difference"])],
+            vec![
+                value_info("minuend", FLOAT, &[1]),
+                value_info("subtrahend", FLOAT, &[1]),
+            ],
+            vec![value_info("difference", FLOAT, &[1])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        let input = |name: Option<&str>, value: f32| SerializedTensor {
+            info: TensorInfo {
+                fact: vec![1],
+                datum_type: ModelDatumType::F32,
+                node_name: name.map(str::to_owned),
+                layout: None,
+            },
+            bytes_data: value.to_le_bytes().to_vec(),
+        };
+        let run = |inputs: &[SerializedTensor]| {
+            model
+                .run_inference(inputs, &InferenceOptions::default())
+                .map(|(outputs, _)| Vec::<f32>::from_le_bytes(&outputs[0].bytes_data).unwrap())
+        };
+
+        let by_name = [input(Some("subtrahend"), 1.0), input(Some("minuend"), 5.0)];
+        assert_eq!(run(&by_name).unwrap(), vec![4.0]);
+        // the unnamed inputs fill the graph inputs no named input took
+        let mixed = [input(None, 1.0), input(Some("minuend"), 5.0)];
+        assert_eq!(run(&mixed).unwrap(), vec![4.0]);
+        assert_eq!(
+            run(&[input(None, 5.0), input(None, 1.0)]).unwrap(),
+            vec![4.0]
+        );
+
+        let err = run(&[input(Some("minuend"), 5.0)]).unwrap_err();
+        assert_eq!(err.to_string(), "Input subtrahend is missing");
+        let err = run(&[input(Some("dividend"), 5.0), input(None, 1.0)]).unwrap_err();
+        assert_eq!(err.to_string(), "The model has no input named dividend");
+        assert!(run(&[input(Some("minuend"), 5.0), input(Some("minuend"), 5.0)]).is_err());
+        assert!(run(&[input(None, 5.0), input(None, 1.0), input(None, 0.0)]).is_err());
+    }
+
+    #[test]
+    fn reproducible_with_a_seed() {
+        let onnx = model(
+            vec![
+                cast("seed", "noise", FLOAT),
+                node("Add", &["input", "noise"], &["noisy"]),
+            ],
+            vec![
+                value_info("input", FLOAT, &[2]),
+                value_info("seed", INT64, &[1]),
+            ],
+            vec![value_info("noisy", FLOAT, &[2])],
+        );
+        let unseeded = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[2])],
+            vec![value_info("relu", FLOAT, &[2])],
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, &onnx);
+        let model =
+            InferenceModel::load_model(&onnx, Uuid::new_v4(), None, digest, &OPTIMIZED).unwrap();
+        let input = [SerializedTensor {
+            info: TensorInfo {
+                fact: vec![2],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [0.5f32, 1.0].as_ref().to_le_bytes(),
+        }];
+        let run = |seed| {
+            let options = InferenceOptions {
+                seed: Some(seed),
+                ..Default::default()
+            };
+            model.check_seed(&options).unwrap();
+            let (outputs, _) = model.run_inference(&input, &options).unwrap();
+            outputs[0].bytes_data.clone()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        assert_eq!(Vec::<f32>::from_le_bytes(&run(2)).unwrap(), vec![2.5, 3.0]);
+        // without a seed the input is missing
+        assert!(model
+            .run_inference(&input, &InferenceOptions::default())
+            .is_err());
+
+        let digest = ring::digest::digest(&ring::digest::SHA256, &unseeded);
+        let relu = InferenceModel::load_model(&unseeded, Uuid::new_v4(), None, digest, &OPTIMIZED)
+            .unwrap();
+        let seeded = InferenceOptions {
+            seed: Some(7),
+            ..Default::default()
+        };
+        assert!(relu.check_seed(&seeded).is_err());
+        assert!(relu.check_seed(&InferenceOptions::default()).is_ok());
     }
 
     fn common_runmodel(uuid: String) {
@@ -456,6 +3095,7 @@ mod tests {
             fact: vec![1, 3, 224, 224],
             datum_type: ModelDatumType::F32,
             node_name: None,
+            layout: None,
         };
         let tensor = SerializedTensor {
             info: info,
@@ -466,10 +3106,13 @@ mod tests {
             .lock()
             .unwrap()
             .use_model(Uuid::from_str(&uuid).unwrap(), |model| {
-                (model.run_inference(vec![tensor.clone()].as_slice()),)
+                (model.run_inference(
+                    vec![tensor.clone()].as_slice(),
+                    &InferenceOptions::default(),
+                ),)
             });
         if let Some(tensor) = res {
-            let result = &tensor.0.expect("Failed to run inference")[0];
+            let result = &tensor.0.expect("Failed to run inference").0[0];
             let tract_tensor =
                 create_tensor::<f32>(&result.bytes_data, result.info.fact.as_slice()).unwrap();
             let arr = tract_tensor
@@ -15,104 +15,1090 @@
 use anyhow::{anyhow, Result};
 use log::*;
 use ring::digest::{self, Digest};
+use serde_derive::{Deserialize, Serialize};
 
-use std::sync::RwLock;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tract_onnx::prelude::{OutletId, TypedModel};
 
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    sync::Arc,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Weak},
 };
 use uuid::Uuid;
 
-use crate::model::{InferenceModel, OnnxModel};
+#[cfg(feature = "diagnostics")]
+use crate::client_communication::SerializedTensor;
+use crate::metrics::{LogMetricsSink, MetricsSink};
+use crate::model::{
+    canonicalize, model_log, InferenceModel, ModelDatumType, ModelOptions, ModelStatus, OnnxModel,
+};
+#[cfg(feature = "diagnostics")]
+use crate::model::{InferenceOptions, NodeTiming, NodeTrace, TraceDetail};
+use crate::reader_gate::{ReaderGate, ReaderPermit};
+use crate::signature::{Ed25519Verifier, ModelSignatureVerifier};
 
 struct InnerModelStore {
     models_by_id: HashMap<Uuid, InferenceModel>,
-    onnx_by_hash: HashMap<Vec<u8>, (usize, Arc<OnnxModel>)>,
+    // graphs of the loaded models by `dedup_key`, an entry dies with the
+    // last model using its graph
+    onnx_by_hash: HashMap<Vec<u8>, Weak<OnnxModel>>,
+    // ids of the named models, only filled when the names are unique
+    models_by_name: HashMap<String, Uuid>,
+    // counted under the write lock, so that a snapshot is consistent
+    models_added: u64,
+    models_deleted: u64,
+    models_evicted: u64,
+    // bumped whenever a model is added or removed
+    generation: u64,
+}
+
+/// Why the store refused a model. The messages are the ones the clients
+/// always got, the variant gives the status code of the reply.
+#[derive(Debug)]
+pub enum ModelStoreError {
+    SignatureInvalid(anyhow::Error),
+    IdCollision(Uuid),
+    NameCollision(String),
+    ModelLoadFailed(anyhow::Error),
+    NotFound(Uuid),
+    Draining(Uuid),
+    Contended(anyhow::Error),
+    OverMemoryBudget { needed: usize, budget: usize },
+}
+
+impl ModelStoreError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ModelStoreError::SignatureInvalid(_) => 403,
+            ModelStoreError::IdCollision(_) | ModelStoreError::NameCollision(_) => 409,
+            ModelStoreError::ModelLoadFailed(_) => 400,
+            ModelStoreError::NotFound(_) => 404,
+            ModelStoreError::Draining(_) | ModelStoreError::Contended(_) => 503,
+            ModelStoreError::OverMemoryBudget { .. } => 413,
+        }
+    }
+}
+
+impl std::fmt::Display for ModelStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelStoreError::SignatureInvalid(err)
+            | ModelStoreError::ModelLoadFailed(err)
+            | ModelStoreError::Contended(err) => {
+                write!(f, "{}", err)
+            }
+            ModelStoreError::IdCollision(_) => write!(f, "UUID collision"),
+            ModelStoreError::NameCollision(name) => {
+                write!(f, "A model named {:?} already exists", name)
+            }
+            ModelStoreError::NotFound(_) => write!(f, "Model doesn't exist"),
+            ModelStoreError::Draining(_) => write!(f, "Draining: the model is being deleted"),
+            ModelStoreError::OverMemoryBudget { needed, budget } => write!(
+                f,
+                "Model needs about {} bytes, more than the {} bytes the store may use",
+                needed, budget
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ModelStoreError {}
+
+/// Lowercase hex rendering of a model hash, the form clients send it back in.
+pub fn hex_hash(model_hash: &Digest) -> String {
+    model_hash
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Approximate bytes taken by `models`, the graphs and copies of the bytes
+/// shared by several models counting once.
+fn memory_used<'a>(models: impl IntoIterator<Item = &'a InferenceModel>) -> usize {
+    let mut graphs = HashSet::new();
+    let mut copies = HashSet::new();
+    let mut used = 0;
+    for model in models {
+        let (graph_size, variant_size) = model.graph_sizes();
+        if graphs.insert(Arc::as_ptr(&model.onnx)) {
+            used += graph_size;
+        }
+        used += variant_size;
+        if let Some(bytes) = model.retained_bytes() {
+            if copies.insert(bytes.as_ptr()) {
+                used += bytes.len();
+            }
+        }
+    }
+    used
+}
+
+fn others_than(
+    models: &InnerModelStore,
+    model_id: Uuid,
+) -> impl Iterator<Item = &InferenceModel> + '_ {
+    let others = models.models_by_id.values();
+    others.filter(move |other| other.model_id() != model_id)
+}
+
+/// Approximate bytes freed by removing `victim` while `others` stay, the
+/// counterpart of `memory_used`.
+fn memory_freed<'a>(
+    victim: &InferenceModel,
+    others: impl IntoIterator<Item = &'a InferenceModel>,
+) -> usize {
+    let (graph_size, variant_size) = victim.graph_sizes();
+    let bytes = victim.retained_bytes();
+    let (mut graph_shared, mut bytes_shared) = (false, false);
+    for other in others {
+        graph_shared |= Arc::ptr_eq(&other.onnx, &victim.onnx);
+        bytes_shared |= matches!(
+            (bytes, other.retained_bytes()),
+            (Some(bytes), Some(other_bytes)) if bytes.as_ptr() == other_bytes.as_ptr()
+        );
+    }
+    let mut freed = variant_size;
+    if !graph_shared {
+        freed += graph_size;
+    }
+    if let Some(bytes) = bytes.filter(|_| !bytes_shared) {
+        freed += bytes.len();
+    }
+    freed
+}
+
+/// Key of a graph in the dedup map: the same bytes loaded with and without
+/// optimization give different graphs.
+fn dedup_key(model_hash: &Digest, optimized: bool) -> Vec<u8> {
+    let mut key = model_hash.as_ref().to_vec();
+    key.push(optimized as u8);
+    key
+}
+
+/// Settings of the model store. The defaults keep the store's historical
+/// behavior.
+#[derive(Debug, Clone, Default)]
+pub struct ModelStoreConfig {
+    /// Retry failed inferences of optimized models against a freshly built
+    /// unoptimized graph. This keeps a copy of the model bytes in memory.
+    pub fallback_on_inference_error: bool,
+    /// Consecutive inference failures after which a model is marked degraded
+    /// and its requests fail fast. `None` disables the circuit breaker.
+    pub circuit_breaker_threshold: Option<u32>,
+    /// How long a degraded model fails fast before being tried again.
+    pub circuit_breaker_cooldown: Duration,
+    /// Largest tensor, in bytes, a node may produce during an inference.
+    /// `None` disables the check.
+    pub max_tensor_size: Option<usize>,
+    /// Longest chain of nodes an uploaded graph may have, checked before
+    /// tract parses it. `None` disables the check.
+    pub max_graph_depth: Option<usize>,
+    /// Period of the background task hashing the models again to detect
+    /// in-memory corruption. This keeps a copy of the model bytes in
+    /// memory. `None` disables the task.
+    pub integrity_check_interval: Option<Duration>,
+    /// Load a separate graph for every upload instead of sharing one between
+    /// the models uploaded with the same bytes. This isolates the models from
+    /// each other at the cost of memory.
+    pub disable_dedup: bool,
+    /// Inferences served at the same time, the others wait or are rejected
+    /// depending on `block_contended_readers`. `None` disables the limit, so
+    /// does 0 which no inference would get past.
+    pub max_concurrent_readers: Option<usize>,
+    /// Make the inferences past `max_concurrent_readers` wait for their turn
+    /// instead of failing with `Contended`.
+    pub block_contended_readers: bool,
+    /// Models kept at most, registering one more evicts another one picked
+    /// by `eviction_policy`. `None` keeps every model.
+    pub max_models: Option<usize>,
+    pub eviction_policy: EvictionPolicy,
+    /// Approximate bytes the models may take in total, registering one more
+    /// evicts others picked by `eviction_policy` until it fits. A model that
+    /// doesn't fit in an empty store is rejected. `None` disables the budget.
+    pub max_store_memory: Option<usize>,
+    /// Models older than this are removed by `sweep_expired`, they are
+    /// counted as evicted. `None` keeps the models until they are deleted.
+    pub model_ttl: Option<Duration>,
+    /// Reject the uploads named like a registered model, so that clients
+    /// can find the models by name.
+    pub unique_names: bool,
+    /// Hash and load the uploads in a canonical form, see `canonicalize`, so
+    /// that different exports of the same model share a graph. The copies
+    /// the store keeps of the bytes are canonical too: the raw bytes are not
+    /// retained, and the hash sent back for an upload is that of its
+    /// canonical form.
+    pub canonicalize_models: bool,
+}
+
+/// Which model makes room when the store is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The model registered first.
+    #[default]
+    Fifo,
+    /// The model that was used the least recently.
+    Lru,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fifo" => Ok(EvictionPolicy::Fifo),
+            "lru" => Ok(EvictionPolicy::Lru),
+            _ => Err(anyhow!("unknown eviction policy {:?}", s)),
+        }
+    }
+}
+
+impl ModelStoreConfig {
+    /// Reads the configuration from the `BLINDAI_*` environment variables.
+    pub fn from_env() -> Self {
+        ModelStoreConfig {
+            fallback_on_inference_error: std::env::var("BLINDAI_FALLBACK_ON_INFERENCE_ERROR")
+                .is_ok(),
+            circuit_breaker_threshold: parse_env("BLINDAI_CIRCUIT_BREAKER_THRESHOLD"),
+            circuit_breaker_cooldown: Duration::from_secs(
+                parse_env("BLINDAI_CIRCUIT_BREAKER_COOLDOWN_SECS").unwrap_or(60),
+            ),
+            max_tensor_size: parse_env("BLINDAI_MAX_TENSOR_SIZE"),
+            max_graph_depth: parse_env("BLINDAI_MAX_GRAPH_DEPTH"),
+            integrity_check_interval: parse_env("BLINDAI_INTEGRITY_CHECK_INTERVAL_SECS")
+                .map(Duration::from_secs),
+            disable_dedup: std::env::var("BLINDAI_DISABLE_DEDUP").is_ok(),
+            max_concurrent_readers: parse_env::<NonZeroUsize>("BLINDAI_MAX_CONCURRENT_READERS")
+                .map(NonZeroUsize::get),
+            block_contended_readers: std::env::var("BLINDAI_BLOCK_CONTENDED_READERS").is_ok(),
+            max_models: parse_env("BLINDAI_MAX_MODELS"),
+            eviction_policy: parse_env("BLINDAI_EVICTION_POLICY").unwrap_or_default(),
+            max_store_memory: parse_env("BLINDAI_MAX_STORE_MEMORY_BYTES"),
+            model_ttl: parse_env("BLINDAI_MODEL_TTL_SECS").map(Duration::from_secs),
+            unique_names: std::env::var("BLINDAI_UNIQUE_MODEL_NAMES").is_ok(),
+            canonicalize_models: std::env::var("BLINDAI_CANONICALIZE_MODELS").is_ok(),
+        }
+    }
+}
+
+pub(crate) fn parse_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring {}: invalid value {:?}", name, value);
+            None
+        }
+    }
+}
+
+/// Version of the tract submodule, keep in sync when updating it.
+pub const TRACT_VERSION: &str = "0.18.2-pre";
+
+/// Identifies the server build, so that clients can tie results to it.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Set at build time through `BLINDAI_BUILD_ID`, e.g. to a commit hash.
+    pub build_id: Option<&'static str>,
+    pub tract_version: &'static str,
+}
+
+/// What the store tells about a model without running it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelDescription {
+    pub model_id: Uuid,
+    pub model_name: Option<String>,
+    #[serde(with = "serde_bytes")]
+    pub model_hash: Vec<u8>,
+    /// `model_hash` as `hex_hash` renders it.
+    pub hex_hash: String,
+    pub optimized: bool,
+    /// Status when the description was taken, the descriptions of a
+    /// snapshot do not follow the later changes.
+    pub status: ModelStatus,
+    pub inputs: Vec<TensorMetadata>,
+    pub outputs: Vec<TensorMetadata>,
+}
+
+impl ModelDescription {
+    fn of(model: &InferenceModel) -> Self {
+        let (inputs, outputs) = match ModelMetadata::of(model) {
+            Ok(metadata) => (metadata.inputs, metadata.outputs),
+            Err(err) => {
+                warn!(
+                    "Could not describe the tensors of model {}: {}",
+                    model.model_id(),
+                    err
+                );
+                (vec![], vec![])
+            }
+        };
+        ModelDescription {
+            model_id: model.model_id(),
+            model_name: model.model_name().map(str::to_owned),
+            model_hash: model.model_hash().as_ref().to_vec(),
+            hex_hash: hex_hash(&model.model_hash()),
+            optimized: model.is_optimized(),
+            status: model.status(),
+            inputs,
+            outputs,
+        }
+    }
+}
+
+/// Metadata of a model without its bytes, to review the catalog of a store,
+/// e.g. before migrating it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub model_id: Uuid,
+    pub model_name: Option<String>,
+    #[serde(with = "serde_bytes")]
+    pub model_hash: Vec<u8>,
+    pub inputs: Vec<TensorMetadata>,
+    pub outputs: Vec<TensorMetadata>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TensorMetadata {
+    pub node_name: String,
+    /// `None` for the datum types clients cannot exchange.
+    pub datum_type: Option<ModelDatumType>,
+    /// `None` for the dimensions only known at inference time.
+    pub shape: Vec<Option<usize>>,
 }
 
+impl ModelMetadata {
+    fn of(model: &InferenceModel) -> Result<Self> {
+        let graph = model.onnx.model();
+        let input_names = graph
+            .input_outlets()?
+            .iter()
+            .map(|outlet| graph.node(outlet.node).name.clone())
+            .collect();
+        let tensors = |outlets: &[OutletId], names: Vec<String>| {
+            outlets
+                .iter()
+                .zip(names)
+                .map(|(&outlet, node_name)| {
+                    let fact = graph.outlet_fact(outlet)?;
+                    Ok(TensorMetadata {
+                        node_name,
+                        datum_type: ModelDatumType::try_from(fact.datum_type).ok(),
+                        shape: fact
+                            .shape
+                            .iter()
+                            .map(|dim| dim.to_i64().ok().map(|dim| dim as usize))
+                            .collect(),
+                    })
+                })
+                .collect::<Result<_>>()
+        };
+        Ok(ModelMetadata {
+            model_id: model.model_id(),
+            model_name: model.model_name().map(str::to_owned),
+            model_hash: model.model_hash().as_ref().to_vec(),
+            inputs: tensors(graph.input_outlets()?, input_names)?,
+            // the names of the outputs, not of the nodes tract lowered them to
+            outputs: tensors(graph.output_outlets()?, model.output_tensor_names()?)?,
+        })
+    }
+}
+
+/// Differences between the models of two stores, by id.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MetadataDiff {
+    pub only_local: Vec<Uuid>,
+    pub only_remote: Vec<Uuid>,
+    /// Present on both sides with different metadata.
+    pub changed: Vec<Uuid>,
+}
+
+/// Immutable view of the models of a store at one point in time.
+#[derive(Debug)]
+pub struct StoreSnapshot {
+    generation: u64,
+    /// Sorted by id.
+    pub models: Vec<ModelDescription>,
+}
+
+/// Number of models described per read lock in `for_each_model`.
+const DESCRIPTION_BATCH: usize = 256;
+
+/// Counters of the store since it was created, taken at one point in time.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StoreStats {
+    #[serde(skip)]
+    pub taken_at: Instant,
+    pub models_added: u64,
+    pub models_deleted: u64,
+    pub models_evicted: u64,
+    pub models_loaded: usize,
+    /// Graphs in memory, the models sharing a graph count once.
+    pub distinct_graphs: usize,
+    /// Approximate bytes taken by the models, bounded by `max_store_memory`.
+    pub memory_used: usize,
+}
+
+/// What happened in the store between two snapshots of its statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StoreStatsDelta {
+    pub interval: Duration,
+    pub models_added: u64,
+    pub models_deleted: u64,
+    pub models_evicted: u64,
+}
+
+impl StoreStats {
+    /// Models per graph in memory, how much the dedup map saves. 1 when the
+    /// store is empty.
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.distinct_graphs {
+            0 => 1.0,
+            graphs => self.models_loaded as f64 / graphs as f64,
+        }
+    }
+
+    /// The counters only grow: going back in time, e.g. comparing with a
+    /// snapshot of another store, gives an empty delta rather than wrapping.
+    pub fn delta(&self, since: &StoreStats) -> StoreStatsDelta {
+        StoreStatsDelta {
+            interval: self.taken_at.saturating_duration_since(since.taken_at),
+            models_added: self.models_added.saturating_sub(since.models_added),
+            models_deleted: self.models_deleted.saturating_sub(since.models_deleted),
+            models_evicted: self.models_evicted.saturating_sub(since.models_evicted),
+        }
+    }
+}
+
+type RemovalListener = Box<dyn Fn(Uuid) + Send + Sync>;
+
+// hex encoded Ed25519 public keys the uploads must be signed with
+const TRUSTED_MODEL_KEYS: &str = "BLINDAI_TRUSTED_MODEL_KEYS";
+// the metrics are logged when set
+const LOG_METRICS: &str = "BLINDAI_LOG_METRICS";
+
 /// This is where model are stored.
 pub struct ModelStore {
     inner: RwLock<InnerModelStore>,
+    config: ModelStoreConfig,
+    reader_gate: Option<ReaderGate>,
+    // logical clock ordering the registrations and uses of the models
+    clock: AtomicU64,
+    signature_verifier: Option<Box<dyn ModelSignatureVerifier>>,
+    metrics_sink: Option<Box<dyn MetricsSink>>,
+    // called with the ids of the models removed or replaced
+    removal_listeners: Mutex<Vec<RemovalListener>>,
+    snapshot: Mutex<Arc<StoreSnapshot>>,
 }
 
 impl ModelStore {
+    /// Creates a store configured from the environment. The uploads must be
+    /// signed by one of the keys of `BLINDAI_TRUSTED_MODEL_KEYS`, when it is
+    /// set, see `Ed25519Verifier`. The metrics are logged when
+    /// `BLINDAI_LOG_METRICS` is set.
     pub fn new() -> Self {
+        let mut store = Self::with_config(ModelStoreConfig::from_env());
+        // keys that can't be parsed trust no upload rather than every one
+        let verifier = parse_env::<Ed25519Verifier>(TRUSTED_MODEL_KEYS)
+            .or_else(|| std::env::var_os(TRUSTED_MODEL_KEYS).map(|_| Ed25519Verifier::new(vec![])));
+        if let Some(verifier) = verifier {
+            store = store.with_signature_verifier(verifier);
+        }
+        if std::env::var(LOG_METRICS).is_ok() {
+            store = store.with_metrics_sink(LogMetricsSink);
+        }
+        store
+    }
+
+    pub fn with_config(config: ModelStoreConfig) -> Self {
         ModelStore {
             inner: RwLock::new(InnerModelStore {
                 models_by_id: HashMap::new(),
                 onnx_by_hash: HashMap::new(),
+                models_by_name: HashMap::new(),
+                models_added: 0,
+                models_deleted: 0,
+                models_evicted: 0,
+                generation: 0,
             }),
+            snapshot: Mutex::new(Arc::new(StoreSnapshot {
+                generation: 0,
+                models: vec![],
+            })),
+            clock: AtomicU64::new(0),
+            signature_verifier: None,
+            metrics_sink: None,
+            removal_listeners: Mutex::new(vec![]),
+            reader_gate: config
+                .max_concurrent_readers
+                .filter(|max_readers| *max_readers > 0)
+                .map(|max_readers| ReaderGate::new(max_readers, config.block_contended_readers)),
+            config,
+        }
+    }
+
+    /// Only accept the uploads whose signature is valid for `verifier`.
+    pub fn with_signature_verifier(
+        mut self,
+        verifier: impl ModelSignatureVerifier + 'static,
+    ) -> Self {
+        self.signature_verifier = Some(Box::new(verifier));
+        self
+    }
+
+    /// Pushes the metrics of the store to `sink` as they happen.
+    pub fn with_metrics_sink(mut self, sink: impl MetricsSink + 'static) -> Self {
+        self.metrics_sink = Some(Box::new(sink));
+        self
+    }
+
+    pub(crate) fn emit(&self, metric: impl FnOnce(&dyn MetricsSink)) {
+        if let Some(sink) = &self.metrics_sink {
+            metric(sink.as_ref());
+        }
+    }
+
+    /// Calls `listener` with the id of every model deleted, evicted, expired
+    /// or replaced, e.g. to drop what was cached for it. The listener runs
+    /// under the write lock of the store and must not use the store.
+    pub fn on_model_removed(&self, listener: impl Fn(Uuid) + Send + Sync + 'static) {
+        self.removal_listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(listener));
+    }
+
+    fn notify_removed(&self, model_id: Uuid) {
+        for listener in self.removal_listeners.lock().unwrap().iter() {
+            listener(model_id);
         }
     }
 
+    fn emit_models_loaded(&self, models: &InnerModelStore) {
+        let loaded = models.models_by_id.len() as f64;
+        self.emit(|sink| sink.set_gauge("models_loaded", loaded, &[]));
+    }
+
+    /// Loads a model uploaded without signature, see `add_signed_model`.
+    #[allow(dead_code)]
     pub fn add_model(
         &self,
         model_bytes: &[u8],
         model_name: Option<String>,
-        optimize: bool,
-    ) -> Result<(Uuid, Digest)> {
-        let model_id = Uuid::new_v4();
-        let model_hash = digest::digest(&digest::SHA256, model_bytes);
+        options: ModelOptions,
+    ) -> Result<(Uuid, Digest), ModelStoreError> {
+        self.add_signed_model(model_bytes, None, model_name, options)
+    }
 
-        let model_hash_vec = model_hash.as_ref().to_vec();
+    /// Loads a model under a new id. The model is loaded without holding
+    /// the lock, then the model and its dedup entry are registered under
+    /// the same write lock as deletions, so a concurrent delete never sees
+    /// a half-registered model.
+    ///
+    /// When the store has a signature verifier, the bytes are only parsed
+    /// once `signature` was checked against them.
+    pub fn add_signed_model(
+        &self,
+        model_bytes: &[u8],
+        signature: Option<&[u8]>,
+        model_name: Option<String>,
+        options: ModelOptions,
+    ) -> Result<(Uuid, Digest), ModelStoreError> {
+        self.add_prepared_model(model_bytes, signature, model_name, options, |_| Ok(()))
+    }
+
+    /// Loads a model like `add_signed_model`, then runs `prepare` on it,
+    /// e.g. to warm it up, before registering it. No request reaches the
+    /// model before `prepare` returns, and a model it fails on is dropped
+    /// without evicting any other.
+    pub fn add_prepared_model(
+        &self,
+        model_bytes: &[u8],
+        signature: Option<&[u8]>,
+        model_name: Option<String>,
+        options: ModelOptions,
+        prepare: impl FnOnce(&InferenceModel) -> Result<()>,
+    ) -> Result<(Uuid, Digest), ModelStoreError> {
+        self.verify_signature(model_bytes, signature)?;
+        let model_id = Uuid::new_v4();
+        let model = self.load_unregistered(model_bytes, model_id, model_name, options)?;
+        let mut model = self.with_store_settings(model);
+        let model_hash = model.model_hash();
+        prepare(&model).map_err(ModelStoreError::ModelLoadFailed)?;
 
         // Create an entry in the hashmap and in the dedup map
         {
             // take the write lock
             let mut models = self.inner.write().unwrap();
+            self.dedup_graph(&mut models, &mut model);
+            self.insert_model(&mut models, model_id, model)?;
+        }
+        info!(
+            "Model {} registered, hash {}",
+            model_id,
+            hex_hash(&model_hash)
+        );
 
-            // HashMap entry api requires only one lookup and should be prefered than .get()
-            // followed with .insert()
-
-            // deduplication support
-            let model = match models.onnx_by_hash.entry(model_hash_vec) {
-                Entry::Occupied(mut entry) => {
-                    let (num, onnx) = entry.get_mut();
-                    *num += 1;
-                    info!("Reusing an existing ONNX entry for model. (n = {})", *num);
-                    InferenceModel::from_onnx_loaded(
-                        Arc::clone(onnx),
-                        model_id,
-                        model_name,
-                        model_hash,
-                    )
-                }
-                Entry::Vacant(entry) => {
-                    info!("Creating a new ONNX entry for model.");
-                    // FIXME(cchudant): this call may take a while to run, we may want to refactor
-                    // this so that the lock  isn't taken here
-                    let model = InferenceModel::load_model(
-                        model_bytes,
-                        model_id,
-                        model_name,
-                        model_hash,
-                        optimize,
-                    )?;
-                    entry.insert((1, Arc::clone(&model.onnx)));
-                    model
-                }
-            };
+        Ok((model_id, model_hash))
+    }
 
-            // actual hashmap insertion
-            match models.models_by_id.entry(model_id) {
-                Entry::Occupied(_) => {
-                    error!(
-                        "UUID collision: model with uuid ({}) already exists.",
-                        model_id
-                    );
-                    return Err(anyhow!("UUID collision"));
+    /// Loads a model streamed from `model_data`, which is hashed as it is
+    /// parsed instead of being buffered first. The graph is still shared with
+    /// the models of the same hash, but only once it was loaded.
+    ///
+    /// Streamed models are not signed, and the stream is buffered anyway
+    /// when the store keeps a copy of the bytes (fallback, integrity checks
+    /// or A/B comparison) or canonicalizes them.
+    pub fn add_model_from_reader(
+        &self,
+        mut model_data: impl Read,
+        model_name: Option<String>,
+        options: ModelOptions,
+    ) -> Result<(Uuid, Digest), ModelStoreError> {
+        if self.signature_verifier.is_some() {
+            return Err(ModelStoreError::SignatureInvalid(anyhow!(
+                "SignatureInvalid: the model is not signed"
+            )));
+        }
+        let needs_bytes = (self.config.fallback_on_inference_error && options.optimize)
+            || self.config.integrity_check_interval.is_some()
+            || options.ab_unoptimized_fraction.is_some()
+            || self.config.canonicalize_models;
+        if needs_bytes {
+            let mut model_bytes = vec![];
+            model_data
+                .read_to_end(&mut model_bytes)
+                .map_err(|err| ModelStoreError::ModelLoadFailed(err.into()))?;
+            return self.add_model(&model_bytes, model_name, options);
+        }
+
+        let model_id = Uuid::new_v4();
+        let options = ModelOptions {
+            max_graph_depth: self.config.max_graph_depth,
+            ..options
+        };
+        let mut model =
+            InferenceModel::load_model_from_reader(model_data, model_id, model_name, &options)
+                .map_err(ModelStoreError::ModelLoadFailed)?;
+        let model_hash = model.model_hash();
+
+        let mut models = self.inner.write().unwrap();
+        self.dedup_graph(&mut models, &mut model);
+        let model = self.with_store_settings(model);
+        self.insert_model(&mut models, model_id, model)?;
+        Ok((model_id, model_hash))
+    }
+
+    /// Loads a model the way `add_signed_model` does and describes it,
+    /// without registering it: the store is left untouched, no model is
+    /// evicted. The description carries the nil id.
+    pub fn validate_model(
+        &self,
+        model_bytes: &[u8],
+        signature: Option<&[u8]>,
+        options: ModelOptions,
+    ) -> Result<ModelMetadata, ModelStoreError> {
+        self.verify_signature(model_bytes, signature)?;
+        let model = self.load_unregistered(model_bytes, Uuid::nil(), None, options)?;
+        ModelMetadata::of(&model).map_err(ModelStoreError::ModelLoadFailed)
+    }
+
+    /// Replaces the bytes of a model, keeping its id and name. The new model
+    /// is loaded without holding the lock, then swapped in under the write
+    /// lock: requests find either the old model or the new one, never none,
+    /// and the inferences running on the old one complete before the swap.
+    /// If the new model cannot be loaded, the old one is left untouched.
+    #[cfg(test)]
+    pub fn replace_model(
+        &self,
+        model_id: Uuid,
+        model_bytes: &[u8],
+        signature: Option<&[u8]>,
+        options: ModelOptions,
+    ) -> Result<Digest, ModelStoreError> {
+        self.replace_prepared_model(model_id, model_bytes, signature, options, |_| Ok(()))
+    }
+
+    /// Replaces a model like `replace_model`, running `prepare` on the new
+    /// one before the swap as `add_prepared_model` does. The new model has to
+    /// fit in the store in place of the old one, other models are evicted to
+    /// make room for it.
+    pub fn replace_prepared_model(
+        &self,
+        model_id: Uuid,
+        model_bytes: &[u8],
+        signature: Option<&[u8]>,
+        options: ModelOptions,
+        prepare: impl FnOnce(&InferenceModel) -> Result<()>,
+    ) -> Result<Digest, ModelStoreError> {
+        self.verify_signature(model_bytes, signature)?;
+        let model_name = self
+            .inner
+            .read()
+            .unwrap()
+            .models_by_id
+            .get(&model_id)
+            .ok_or(ModelStoreError::NotFound(model_id))?
+            .model_name()
+            .map(str::to_owned);
+        let model = self.load_unregistered(model_bytes, model_id, model_name, options)?;
+        let mut model = self.with_store_settings(model);
+        let model_hash = model.model_hash();
+        prepare(&model).map_err(ModelStoreError::ModelLoadFailed)?;
+
+        let mut models = self.inner.write().unwrap();
+        // the model may have been deleted while the new one was loading
+        if !models.models_by_id.contains_key(&model_id) {
+            return Err(ModelStoreError::NotFound(model_id));
+        }
+        self.dedup_graph(&mut models, &mut model);
+        self.make_room(&mut models, model_id, &model)?;
+        model.set_loaded_at(self.clock.fetch_add(1, Ordering::Relaxed));
+        info!("Replacing model {}", model_id);
+        // the graph of the old model dies with it, unless other models share it
+        models.models_by_id.insert(model_id, model);
+        models.generation += 1;
+        // what was kept for the old model does not hold for the new one
+        self.notify_removed(model_id);
+        self.emit(|sink| sink.incr("models_replaced", &[]));
+        self.emit_models_loaded(&models);
+        Ok(model_hash)
+    }
+
+    fn verify_signature(
+        &self,
+        model_bytes: &[u8],
+        signature: Option<&[u8]>,
+    ) -> Result<(), ModelStoreError> {
+        match (&self.signature_verifier, signature) {
+            (None, _) => Ok(()),
+            (Some(verifier), Some(signature)) => verifier
+                .verify(model_bytes, signature)
+                .map_err(ModelStoreError::SignatureInvalid),
+            (Some(_), None) => Err(ModelStoreError::SignatureInvalid(anyhow!(
+                "SignatureInvalid: the model is not signed"
+            ))),
+        }
+    }
+
+    /// Loads a model without registering it, sharing the graph of a model
+    /// loaded with the same bytes if there is one. The lock is only taken to
+    /// look that graph up, inferences keep running while the model loads.
+    fn load_unregistered(
+        &self,
+        model_bytes: &[u8],
+        model_id: Uuid,
+        model_name: Option<String>,
+        options: ModelOptions,
+    ) -> Result<InferenceModel, ModelStoreError> {
+        let options = ModelOptions {
+            max_graph_depth: self.config.max_graph_depth,
+            ..options
+        };
+        let log_level = options.log_level;
+        let canonical;
+        let model_bytes = if self.config.canonicalize_models {
+            canonical = canonicalize(model_bytes).map_err(ModelStoreError::ModelLoadFailed)?;
+            &canonical[..]
+        } else {
+            model_bytes
+        };
+        let model_hash = digest::digest(&digest::SHA256, model_bytes);
+
+        let shared = match self.config.disable_dedup {
+            true => None,
+            false => self
+                .inner
+                .read()
+                .unwrap()
+                .onnx_by_hash
+                .get(&dedup_key(&model_hash, options.optimize))
+                .and_then(Weak::upgrade),
+        };
+        let model = match shared {
+            Some(onnx) => {
+                model_log!(
+                    log_level,
+                    Level::Info,
+                    "Reusing an existing ONNX entry for model. (n = {})",
+                    Arc::strong_count(&onnx)
+                );
+                InferenceModel::from_onnx_loaded(onnx, model_id, model_name, model_hash, &options)
+                    .map(|model| model.with_graph_size(model_bytes.len()))
+            }
+            None => {
+                model_log!(
+                    log_level,
+                    Level::Info,
+                    "Creating a new ONNX entry for model."
+                );
+                InferenceModel::load_model(model_bytes, model_id, model_name, model_hash, &options)
+            }
+        }
+        .map_err(ModelStoreError::ModelLoadFailed)?;
+        let model = if self.config.fallback_on_inference_error && options.optimize {
+            model.with_fallback(model_bytes)
+        } else {
+            model
+        };
+        let model = if self.config.integrity_check_interval.is_some() {
+            model.retain_bytes(model_bytes)
+        } else {
+            model
+        };
+        match options.ab_unoptimized_fraction {
+            Some(fraction) => model
+                .with_ab_variant(model_bytes, fraction)
+                .map_err(ModelStoreError::ModelLoadFailed),
+            None => Ok(model),
+        }
+    }
+
+    /// Makes a model about to be registered use the graph registered for
+    /// the same hash, if any, and records its graph in the dedup map. The
+    /// same bytes may have been loaded by another upload in the meantime,
+    /// the graph loaded for this one is then dropped. An entry left by a
+    /// failed registration dies with the model.
+    fn dedup_graph(&self, models: &mut InnerModelStore, model: &mut InferenceModel) {
+        models
+            .onnx_by_hash
+            .retain(|_, onnx| onnx.strong_count() > 0);
+        if self.config.disable_dedup {
+            return;
+        }
+        let key = dedup_key(&model.model_hash(), model.is_optimized());
+        if let Some(onnx) = models.onnx_by_hash.get(&key).and_then(Weak::upgrade) {
+            model.onnx = onnx;
+            self.emit(|sink| sink.incr("dedup_hits", &[]));
+        }
+        models.onnx_by_hash.insert(key, Arc::downgrade(&model.onnx));
+    }
+
+    /// Registers the graph of an existing model under a new id, without
+    /// reloading it. The clone keeps the settings of the original unless
+    /// `options` are given, but always shares its optimization, and
+    /// outlives the original.
+    pub fn clone_model(
+        &self,
+        src_id: Uuid,
+        model_name: Option<String>,
+        options: Option<ModelOptions>,
+    ) -> Result<(Uuid, Digest)> {
+        let model_id = Uuid::new_v4();
+        let mut models = self.inner.write().unwrap();
+
+        let src = models
+            .models_by_id
+            .get(&src_id)
+            .ok_or_else(|| anyhow!("Model doesn't exist"))?;
+        if src.is_draining() {
+            return Err(ModelStoreError::Draining(src_id).into());
+        }
+        let model = src.clone_as(model_id, model_name, options)?;
+        let model_hash = model.model_hash();
+        let model = self.with_store_settings(model);
+        // the clone keeps the dedup entry of the shared graph alive, even if
+        // registering it evicts the original
+        self.insert_model(&mut models, model_id, model)?;
+        Ok((model_id, model_hash))
+    }
+
+    /// Registers a modified copy of an existing model's graph, e.g. with an
+    /// input dimension fixed or a head stripped, without going through ONNX.
+    #[allow(dead_code)]
+    pub fn fork_model(
+        &self,
+        src_id: Uuid,
+        model_name: Option<String>,
+        transform: impl FnOnce(&mut TypedModel) -> Result<()>,
+    ) -> Result<(Uuid, Digest)> {
+        let model_id = Uuid::new_v4();
+        // the fork is built under the read lock, inferences can still run
+        let model = {
+            let read_guard = self.inner.read().unwrap();
+            let src = read_guard
+                .models_by_id
+                .get(&src_id)
+                .ok_or_else(|| anyhow!("Model doesn't exist"))?;
+            if src.is_draining() {
+                return Err(ModelStoreError::Draining(src_id).into());
+            }
+            src.fork(model_id, model_name, transform)?
+        };
+        let model_hash = model.model_hash();
+        let mut model = self.with_store_settings(model);
+
+        let mut models = self.inner.write().unwrap();
+        // an identical fork may already exist, it then shares its graph
+        self.dedup_graph(&mut models, &mut model);
+        self.insert_model(&mut models, model_id, model)?;
+        Ok((model_id, model_hash))
+    }
+
+    fn with_store_settings(&self, model: InferenceModel) -> InferenceModel {
+        let model = match self.config.circuit_breaker_threshold {
+            Some(threshold) => {
+                model.with_circuit_breaker(threshold, self.config.circuit_breaker_cooldown)
+            }
+            None => model,
+        };
+        match self.config.max_tensor_size {
+            Some(max_tensor_size) => model.with_max_tensor_size(max_tensor_size),
+            None => model,
+        }
+    }
+
+    fn insert_model(
+        &self,
+        models: &mut InnerModelStore,
+        model_id: Uuid,
+        mut model: InferenceModel,
+    ) -> Result<(), ModelStoreError> {
+        if models.models_by_id.contains_key(&model_id) {
+            error!(
+                "UUID collision: model with uuid ({}) already exists.",
+                model_id
+            );
+            return Err(ModelStoreError::IdCollision(model_id));
+        }
+        let indexed_name = model
+            .model_name()
+            .filter(|_| self.config.unique_names)
+            .map(str::to_owned);
+        if let Some(name) = &indexed_name {
+            if models.models_by_name.contains_key(name) {
+                return Err(ModelStoreError::NameCollision(name.clone()));
+            }
+        }
+        self.make_room(models, model_id, &model)?;
+        // actual hashmap insertion
+        model.set_loaded_at(self.clock.fetch_add(1, Ordering::Relaxed));
+        models.models_by_id.insert(model_id, model);
+        if let Some(name) = indexed_name {
+            models.models_by_name.insert(name, model_id);
+        }
+        models.models_added += 1;
+        models.generation += 1;
+        self.emit(|sink| sink.incr("models_added", &[]));
+        self.emit_models_loaded(models);
+        Ok(())
+    }
+
+    /// Evicts the models picked by `eviction_policy` until `model` fits
+    /// within `max_models` and `max_store_memory`. A registered model with the
+    /// id of `model` is the one it replaces: it is neither counted nor
+    /// evicted.
+    ///
+    /// Evicting a model that shares the graph of `model` frees none of it,
+    /// such models are only evicted to respect `max_models`.
+    fn make_room(
+        &self,
+        models: &mut InnerModelStore,
+        model_id: Uuid,
+        model: &InferenceModel,
+    ) -> Result<(), ModelStoreError> {
+        let budget = self.config.max_store_memory;
+        if let Some(budget) = budget {
+            let needed = memory_used([model]);
+            if needed > budget {
+                return Err(ModelStoreError::OverMemoryBudget { needed, budget });
+            }
+        }
+        // kept up to date with the evictions rather than computed again
+        let mut used = match budget {
+            Some(_) => memory_used(others_than(models, model_id).chain([model])),
+            None => 0,
+        };
+        loop {
+            let count =
+                models.models_by_id.len() - models.models_by_id.contains_key(&model_id) as usize;
+            let (reason, budget) = match (self.config.max_models, budget) {
+                (Some(max_models), _) if count >= max_models => ("capacity", None),
+                (_, Some(budget)) if used > budget => ("memory", Some(budget)),
+                _ => break,
+            };
+            let candidates = others_than(models, model_id)
+                .filter(|other| budget.is_none() || !Arc::ptr_eq(&other.onnx, &model.onnx));
+            let victim = match self.config.eviction_policy {
+                EvictionPolicy::Fifo => candidates.min_by_key(|model| model.loaded_at()),
+                EvictionPolicy::Lru => candidates.min_by_key(|model| model.last_used()),
+            };
+            let victim = match (victim, budget) {
+                (Some(victim), _) => victim,
+                (None, Some(budget)) => {
+                    return Err(ModelStoreError::OverMemoryBudget {
+                        needed: used,
+                        budget,
+                    })
                 }
-                Entry::Vacant(entry) => entry.insert(model),
+                (None, None) => break,
             };
+            let victim_id = victim.model_id();
+            if self.config.max_store_memory.is_some() {
+                used -= memory_freed(
+                    victim,
+                    others_than(models, model_id)
+                        .filter(|other| other.model_id() != victim_id)
+                        .chain([model]),
+                );
+            }
+            info!("Evicting model {} to make room for {}", victim_id, model_id);
+            self.remove_model(models, victim_id);
+            models.models_evicted += 1;
+            self.emit(|sink| sink.incr("models_evicted", &[("reason", reason)]));
         }
+        Ok(())
+    }
 
-        Ok((model_id, model_hash))
+    // the dedup entry of the model's graph dies with the last model using it
+    fn remove_model(&self, models: &mut InnerModelStore, model_id: Uuid) -> Option<InferenceModel> {
+        let model = models.models_by_id.remove(&model_id)?;
+        if let Some(name) = model.model_name() {
+            if models.models_by_name.get(name) == Some(&model_id) {
+                models.models_by_name.remove(name);
+            }
+        }
+        models.generation += 1;
+        self.notify_removed(model_id);
+        Some(model)
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let read_guard = self.inner.read().unwrap();
+        StoreStats {
+            taken_at: Instant::now(),
+            models_added: read_guard.models_added,
+            models_deleted: read_guard.models_deleted,
+            models_evicted: read_guard.models_evicted,
+            models_loaded: read_guard.models_by_id.len(),
+            distinct_graphs: read_guard
+                .models_by_id
+                .values()
+                .map(|model| Arc::as_ptr(&model.onnx))
+                .collect::<HashSet<_>>()
+                .len(),
+            memory_used: memory_used(read_guard.models_by_id.values()),
+        }
+    }
+
+    /// Hash of a model, as `hex_hash` renders it.
+    #[allow(dead_code)]
+    pub fn get_model_hash(&self, model_id: &str) -> Option<String> {
+        let model_id = Uuid::from_str(model_id).ok()?;
+        let read_guard = self.inner.read().unwrap();
+        let model = read_guard.models_by_id.get(&model_id)?;
+        Some(hex_hash(&model.model_hash()))
     }
 
     pub fn get_uuid_from_hash(&self, model_hash: &str) -> Option<Uuid> {
         let read_guard = self.inner.read().unwrap();
-        let digest = ring::test::from_hex(model_hash).unwrap();
+        let digest = ring::test::from_hex(model_hash).ok()?;
         for val in read_guard.models_by_id.iter() {
             if val.1.model_hash().as_ref() == &digest[..] {
                 return Some(val.0.to_owned());
@@ -121,31 +1107,2103 @@ impl ModelStore {
         None
     }
 
+    pub fn build_info(&self) -> BuildInfo {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            build_id: option_env!("BLINDAI_BUILD_ID"),
+            tract_version: TRACT_VERSION,
+        }
+    }
+
+    /// Merkle root over the (id, hash) pairs of the loaded models, for a
+    /// remote verifier to check exactly which models are served.
+    ///
+    /// Leaves are sorted by id and computed as `SHA256(0x00 || id || hash)`,
+    /// inner nodes as `SHA256(0x01 || left || right)`. A node without a
+    /// sibling is carried to the next level as is. An empty store has the
+    /// hash of nothing as root.
+    pub fn models_merkle_root(&self) -> Vec<u8> {
+        let mut leaves: Vec<_> = {
+            let read_guard = self.inner.read().unwrap();
+            read_guard
+                .models_by_id
+                .iter()
+                .map(|(id, model)| (*id, model.model_hash()))
+                .collect()
+        };
+        leaves.sort_by_key(|(id, _)| *id);
+
+        let hash_node = |prefix: u8, parts: &[&[u8]]| {
+            let mut context = digest::Context::new(&digest::SHA256);
+            context.update(&[prefix]);
+            for part in parts {
+                context.update(part);
+            }
+            context.finish().as_ref().to_vec()
+        };
+        let mut level: Vec<Vec<u8>> = leaves
+            .iter()
+            .map(|(id, hash)| hash_node(0, &[id.as_bytes(), hash.as_ref()]))
+            .collect();
+        if level.is_empty() {
+            return digest::digest(&digest::SHA256, &[]).as_ref().to_vec();
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(1, &[left, right]),
+                    [single] => single.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        level.remove(0)
+    }
+
+    /// Hashes the retained copies of the models again, returns the models
+    /// whose copy no longer matches the hash computed at upload.
+    pub fn verify_integrity(&self) -> Vec<Uuid> {
+        let read_guard = self.inner.read().unwrap();
+        let mut corrupted = vec![];
+        for (model_id, model) in read_guard.models_by_id.iter() {
+            if model.verify_integrity() == Some(false) {
+                error!(
+                    "Integrity check failed: model {} no longer matches its hash",
+                    model_id
+                );
+                corrupted.push(*model_id);
+            }
+        }
+        corrupted
+    }
+
+    /// Spawns the thread checking the integrity of the models, if enabled.
+    pub fn spawn_integrity_check(self: &Arc<Self>) {
+        if let Some(period) = self.config.integrity_check_interval {
+            let model_store = Arc::clone(self);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(period);
+                model_store.verify_integrity();
+            });
+        }
+    }
+
+    /// Removes the models registered more than `model_ttl` ago, returns how
+    /// many were removed. Inferences already running on them complete.
+    pub fn sweep_expired(&self) -> usize {
+        let ttl = match self.config.model_ttl {
+            Some(ttl) => ttl,
+            None => return 0,
+        };
+        let mut write_guard = self.inner.write().unwrap();
+        let expired: Vec<Uuid> = write_guard
+            .models_by_id
+            .values()
+            .filter(|model| model.age() >= ttl)
+            .map(InferenceModel::model_id)
+            .collect();
+        for model_id in &expired {
+            info!("Model {} expired", model_id);
+            self.remove_model(&mut write_guard, *model_id);
+            self.emit(|sink| sink.incr("models_evicted", &[("reason", "expired")]));
+        }
+        write_guard.models_evicted += expired.len() as u64;
+        if !expired.is_empty() {
+            self.emit_models_loaded(&write_guard);
+        }
+        expired.len()
+    }
+
+    /// Spawns the thread removing the expired models, if models expire.
+    pub fn spawn_expiry_sweeper(self: &Arc<Self>) {
+        if let Some(ttl) = self.config.model_ttl {
+            let model_store = Arc::clone(self);
+            let period = ttl.clamp(Duration::from_secs(1), Duration::from_secs(60));
+            std::thread::spawn(move || loop {
+                std::thread::sleep(period);
+                model_store.sweep_expired();
+            });
+        }
+    }
+
+    /// Version of a model: the position of its registration among all the
+    /// registrations of the store. It only grows, and a model registered
+    /// again under the same id gets a new one, unlike its hash it does not
+    /// depend on the content of the model.
+    #[allow(dead_code)]
+    pub fn model_version(&self, model_id: Uuid) -> Option<u64> {
+        let read_guard = self.inner.read().unwrap();
+        read_guard
+            .models_by_id
+            .get(&model_id)
+            .map(InferenceModel::loaded_at)
+    }
+
+    /// Sets or clears the log level override of a model, returns false if
+    /// the model doesn't exist.
+    pub fn set_model_log_level(&self, model_id: Uuid, log_level: Option<LevelFilter>) -> bool {
+        self.use_model(model_id, |model| model.set_log_level(log_level))
+            .is_some()
+    }
+
+    /// Clears the degraded state of a model, returns false if the model
+    /// doesn't exist or has no circuit breaker.
+    pub fn reset_circuit_breaker(&self, model_id: Uuid) -> bool {
+        self.use_model(model_id, InferenceModel::reset_circuit_breaker)
+            .unwrap_or(false)
+    }
+
+    /// Waits for, or fails to get, a turn among the concurrent readers when
+    /// `max_concurrent_readers` is set. The turn ends when the permit is
+    /// dropped.
+    pub(crate) fn admit_reader(&self) -> Result<Option<ReaderPermit<'_>>> {
+        self.reader_gate.as_ref().map(ReaderGate::enter).transpose()
+    }
+
     pub fn use_model<U>(&self, model_id: Uuid, fun: impl Fn(&InferenceModel) -> U) -> Option<U> {
+        self.try_use_model(model_id, fun).ok()
+    }
+
+    /// Runs `fun` on a model, or tells why it can't: the model doesn't exist
+    /// or is being drained by `drain_and_delete`.
+    pub fn try_use_model<U>(
+        &self,
+        model_id: Uuid,
+        fun: impl FnOnce(&InferenceModel) -> U,
+    ) -> Result<U, ModelStoreError> {
         // take a read lock
         let read_guard = self.inner.read().unwrap();
-        read_guard.models_by_id.get(&model_id).map(fun)
+        let model = read_guard
+            .models_by_id
+            .get(&model_id)
+            .ok_or(ModelStoreError::NotFound(model_id))?;
+        if model.is_draining() {
+            return Err(ModelStoreError::Draining(model_id));
+        }
+        // recorded under the read lock, uses don't wait for each other
+        model.touch(self.clock.fetch_add(1, Ordering::Relaxed));
+        let _in_flight = model.start_use();
+        let start = Instant::now();
+        let result = fun(model);
+        let elapsed = start.elapsed().as_secs_f64();
+        self.emit(|sink| {
+            sink.incr("model_uses", &[]);
+            sink.observe("model_use_seconds", elapsed, &[]);
+        });
+        Ok(result)
+    }
+
+    /// Runs the same zero-filled input `runs` times and checks that every run
+    /// produced bit-identical outputs. Models that fail this check should not
+    /// have their results cached.
+    #[cfg(feature = "diagnostics")]
+    #[allow(dead_code)]
+    pub fn check_determinism(&self, model_id: Uuid, runs: usize) -> Result<bool> {
+        self.use_model(model_id, |model| {
+            let inputs = model.zero_inputs()?;
+            let (reference, _) = model.run_inference(&inputs, &InferenceOptions::default())?;
+            for run in 1..runs {
+                let (outputs, _) = model.run_inference(&inputs, &InferenceOptions::default())?;
+                let identical = outputs
+                    .iter()
+                    .map(|tensor| &tensor.bytes_data)
+                    .eq(reference.iter().map(|tensor| &tensor.bytes_data));
+                if !identical {
+                    warn!(
+                        "Model {} produced different outputs on run {}",
+                        model_id, run
+                    );
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+        .ok_or_else(|| anyhow!("Model doesn't exist"))?
+    }
+
+    /// Runs an inference on a model recording the outputs of every node, see
+    /// [`InferenceModel::run_with_trace`].
+    #[cfg(feature = "diagnostics")]
+    #[allow(dead_code)]
+    pub fn run_with_trace(
+        &self,
+        model_id: Uuid,
+        inputs: &[SerializedTensor],
+        detail: TraceDetail,
+    ) -> Result<(Vec<SerializedTensor>, Vec<NodeTrace>)> {
+        self.use_model(model_id, |model| model.run_with_trace(inputs, detail))
+            .ok_or_else(|| anyhow!("Model doesn't exist"))?
+    }
+
+    /// Runs an inference on a model timing every node, see
+    /// [`InferenceModel::run_with_profile`].
+    #[cfg(feature = "diagnostics")]
+    #[allow(dead_code)]
+    pub fn run_with_profile(
+        &self,
+        model_id: Uuid,
+        inputs: &[SerializedTensor],
+    ) -> Result<(Vec<SerializedTensor>, Vec<NodeTiming>, Duration)> {
+        self.use_model(model_id, |model| model.run_with_profile(inputs))
+            .ok_or_else(|| anyhow!("Model doesn't exist"))?
+    }
+
+    /// Metadata of every model, sorted by id.
+    #[allow(dead_code)]
+    pub fn export_metadata(&self) -> Result<Vec<ModelMetadata>> {
+        let read_guard = self.inner.read().unwrap();
+        let mut metadata = read_guard
+            .models_by_id
+            .values()
+            .map(ModelMetadata::of)
+            .collect::<Result<Vec<_>>>()?;
+        metadata.sort_by_key(|model| model.model_id);
+        Ok(metadata)
+    }
+
+    /// Compares the models of this store with the metadata exported by
+    /// another one. The lists of ids are sorted.
+    #[allow(dead_code)]
+    pub fn diff_metadata(&self, remote: &[ModelMetadata]) -> Result<MetadataDiff> {
+        let local = self.export_metadata()?;
+        let remote: HashMap<_, _> = remote.iter().map(|model| (model.model_id, model)).collect();
+        let mut diff = MetadataDiff::default();
+        for model in &local {
+            match remote.get(&model.model_id) {
+                None => diff.only_local.push(model.model_id),
+                Some(&remote_model) if remote_model != model => diff.changed.push(model.model_id),
+                Some(_) => {}
+            }
+        }
+        let local: HashSet<_> = local.iter().map(|model| model.model_id).collect();
+        diff.only_remote = remote
+            .keys()
+            .filter(|model_id| !local.contains(model_id))
+            .copied()
+            .collect();
+        diff.only_remote.sort();
+        Ok(diff)
+    }
+
+    /// Describes every model in a snapshot that can be read for as long as
+    /// needed without holding any lock of the store.
+    ///
+    /// The snapshot is rebuilt, under the read lock, by the first call after
+    /// a model was added or removed: a snapshot is never older than the last
+    /// write that happened before the call, but it does not follow the writes
+    /// happening while it is read.
+    pub fn snapshot(&self) -> Arc<StoreSnapshot> {
+        let read_guard = self.inner.read().unwrap();
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if snapshot.generation != read_guard.generation {
+            let mut models: Vec<_> = read_guard
+                .models_by_id
+                .values()
+                .map(ModelDescription::of)
+                .collect();
+            models.sort_by_key(|model| model.model_id);
+            *snapshot = Arc::new(StoreSnapshot {
+                generation: read_guard.generation,
+                models,
+            });
+        }
+        Arc::clone(&snapshot)
+    }
+
+    /// Calls `fun` with the description of every model, without building the
+    /// whole list at once.
+    ///
+    /// The ids are snapshot first, then the models are described in batches,
+    /// each under its own read lock, and `fun` is called with no lock held.
+    /// Models added during the iteration are not visited, models deleted
+    /// before their batch is reached are skipped.
+    #[allow(dead_code)]
+    pub fn for_each_model(&self, mut fun: impl FnMut(&ModelDescription)) {
+        let model_ids: Vec<Uuid> = self
+            .inner
+            .read()
+            .unwrap()
+            .models_by_id
+            .keys()
+            .copied()
+            .collect();
+        for batch in model_ids.chunks(DESCRIPTION_BATCH) {
+            let descriptions: Vec<_> = {
+                let read_guard = self.inner.read().unwrap();
+                batch
+                    .iter()
+                    .filter_map(|model_id| read_guard.models_by_id.get(model_id))
+                    .map(ModelDescription::of)
+                    .collect()
+            };
+            descriptions.iter().for_each(&mut fun);
+        }
     }
 
+    /// Describes every model, sorted by id. The descriptions come from the
+    /// snapshot, only their status is read again.
+    pub fn list_models(&self) -> Vec<ModelDescription> {
+        let snapshot = self.snapshot();
+        let read_guard = self.inner.read().unwrap();
+        snapshot
+            .models
+            .iter()
+            .filter_map(|description| {
+                let model = read_guard.models_by_id.get(&description.model_id)?;
+                Some(ModelDescription {
+                    status: model.status(),
+                    ..description.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// Id of the model registered under `name`, only known when the store
+    /// keeps the names unique.
+    #[allow(dead_code)]
+    pub fn model_id_by_name(&self, name: &str) -> Option<Uuid> {
+        let read_guard = self.inner.read().unwrap();
+        read_guard.models_by_name.get(name).copied()
+    }
+
+    /// Removes a model, if it is present when the write lock is taken. When
+    /// several deletions of the same model race, only one of them returns it.
     pub fn delete_model(&self, model_id: Uuid) -> Option<InferenceModel> {
         let mut write_guard = self.inner.write().unwrap();
+        let model = self.remove_model(&mut write_guard, model_id)?;
+        write_guard.models_deleted += 1;
+        self.emit(|sink| sink.incr("models_deleted", &[]));
+        self.emit_models_loaded(&write_guard);
+        Some(model)
+    }
 
-        let model = match write_guard.models_by_id.entry(model_id) {
-            Entry::Occupied(entry) => entry.remove(),
-            Entry::Vacant(_) => return None,
+    /// Deletes a model once the requests it is serving completed. New
+    /// requests, and other deletions, fail fast with `Draining` in the
+    /// meantime. If the requests don't complete within `timeout` the model is
+    /// left registered, and serves requests again.
+    pub fn drain_and_delete(
+        &self,
+        model_id: Uuid,
+        timeout: Duration,
+    ) -> Result<(), ModelStoreError> {
+        {
+            let read_guard = self.inner.read().unwrap();
+            let model = read_guard
+                .models_by_id
+                .get(&model_id)
+                .ok_or(ModelStoreError::NotFound(model_id))?;
+            if model.start_draining() {
+                return Err(ModelStoreError::Draining(model_id));
+            }
+        }
+        // the model is looked up again on every poll, it may have been
+        // deleted or replaced in the meantime
+        let in_flight = |draining: bool| {
+            let read_guard = self.inner.read().unwrap();
+            let model = read_guard.models_by_id.get(&model_id)?;
+            model.set_draining(draining);
+            Some(model.in_flight())
         };
+        let deadline = Instant::now() + timeout;
+        loop {
+            match in_flight(true) {
+                None => return Err(ModelStoreError::NotFound(model_id)),
+                Some(0) => break,
+                Some(_) if Instant::now() >= deadline => {
+                    in_flight(false);
+                    return Err(ModelStoreError::Contended(anyhow!(
+                        "Contended: model {} is still serving requests",
+                        model_id
+                    )));
+                }
+                Some(_) => std::thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        self.delete_model(model_id)
+            .map(|_| ())
+            .ok_or(ModelStoreError::NotFound(model_id))
+    }
 
-        if let Entry::Occupied(mut entry) = write_guard
+    /// Deletes several models under a single write lock. The result lines up
+    /// with `model_ids`, `None` for the ids that had no model.
+    pub fn delete_models(&self, model_ids: &[Uuid]) -> Vec<Option<InferenceModel>> {
+        let mut write_guard = self.inner.write().unwrap();
+        let deleted: Vec<_> = model_ids
+            .iter()
+            .map(|&model_id| self.remove_model(&mut write_guard, model_id))
+            .collect();
+        let count = deleted.iter().flatten().count();
+        write_guard.models_deleted += count as u64;
+        for _ in 0..count {
+            self.emit(|sink| sink.incr("models_deleted", &[]));
+        }
+        if count > 0 {
+            self.emit_models_loaded(&write_guard);
+        }
+        deleted
+    }
+
+    /// Checks that the dedup map agrees with the registered models: every
+    /// model uses the graph its dedup entry points to.
+    #[cfg(any(test, feature = "diagnostics"))]
+    #[allow(dead_code)]
+    pub fn self_check(&self) -> Result<()> {
+        let read_guard = self.inner.read().unwrap();
+        if self.config.disable_dedup {
+            if !read_guard.onnx_by_hash.is_empty() {
+                return Err(anyhow!("The dedup map is used while dedup is disabled"));
+            }
+            return Ok(());
+        }
+        for (model_id, model) in read_guard.models_by_id.iter() {
+            match read_guard
+                .onnx_by_hash
+                .get(&dedup_key(&model.model_hash(), model.is_optimized()))
+                .and_then(Weak::upgrade)
+            {
+                Some(onnx) if Arc::ptr_eq(&onnx, &model.onnx) => {}
+                Some(_) => return Err(anyhow!("Model {} doesn't use the shared graph", model_id)),
+                None => return Err(anyhow!("Model {} has no dedup entry", model_id)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_communication::{SerializedTensor, TensorInfo};
+    use crate::model::test_graphs::*;
+    use crate::model::{InferenceOptions, ModelDatumType};
+    use std::sync::atomic::AtomicBool;
+
+    // graphs of the dedup map still used by a model
+    fn live_graphs(store: &ModelStore) -> usize {
+        store
+            .inner
+            .read()
+            .unwrap()
             .onnx_by_hash
-            .entry(model.model_hash().as_ref().to_vec())
-        {
-            let (i, _) = entry.get_mut();
-            *i -= 1;
-            if *i == 0 {
-                entry.remove();
+            .values()
+            .filter(|onnx| onnx.strong_count() > 0)
+            .count()
+    }
+
+    #[cfg(feature = "diagnostics")]
+    static MOBILENET: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/mobilenet/mobilenetv2-7.onnx"
+    ));
+
+    #[test]
+    fn build_info_carries_the_versions() {
+        let build_info = ModelStore::with_config(ModelStoreConfig::default()).build_info();
+        assert_eq!(build_info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(build_info.tract_version, TRACT_VERSION);
+
+        let value: serde_cbor::Value =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&build_info).unwrap()).unwrap();
+        let version = match value {
+            serde_cbor::Value::Map(map) => {
+                map[&serde_cbor::Value::Text("version".to_string())].clone()
+            }
+            _ => panic!("BuildInfo should serialize to a map"),
+        };
+        assert_eq!(
+            version,
+            serde_cbor::Value::Text(env!("CARGO_PKG_VERSION").to_string())
+        );
+    }
+
+    #[test]
+    fn merkle_root_tracks_the_model_set() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let empty = store.models_merkle_root();
+
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let one = store.models_merkle_root();
+        assert_ne!(one, empty);
+        assert_eq!(store.models_merkle_root(), one);
+
+        let (second, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let two = store.models_merkle_root();
+        assert_ne!(two, one);
+        assert_eq!(store.models_merkle_root(), two);
+
+        store.delete_model(second).unwrap();
+        assert_eq!(store.models_merkle_root(), one);
+        store.delete_model(first).unwrap();
+        assert_eq!(store.models_merkle_root(), empty);
+    }
+
+    #[test]
+    fn clone_outlives_the_original() {
+        let graph = model(
+            vec![constant("values", &[1.0, 2.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[2])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            integrity_check_interval: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        });
+        let (original, hash) = store
+            .add_model(
+                &graph,
+                Some("original".to_string()),
+                ModelOptions::default(),
+            )
+            .unwrap();
+        let (clone, clone_hash) = store
+            .clone_model(original, Some("clone".to_string()), None)
+            .unwrap();
+        assert_ne!(clone, original);
+        assert_eq!(clone_hash.as_ref(), hash.as_ref());
+        assert!(store.clone_model(Uuid::new_v4(), None, None).is_err());
+
+        let shares_graph = |model_id| {
+            store
+                .use_model(model_id, |model| Arc::clone(&model.onnx))
+                .unwrap()
+        };
+        assert!(Arc::ptr_eq(&shares_graph(original), &shares_graph(clone)));
+
+        store.delete_model(original).unwrap();
+        // the copy of the model is shared as well
+        assert!(store.verify_integrity().is_empty());
+        let outputs = store
+            .use_model(clone, |model| {
+                assert_eq!(model.model_name(), Some("clone"));
+                model
+                    .run_inference(&[] as &[SerializedTensor], &InferenceOptions::default())
+                    .unwrap()
+                    .0
+            })
+            .unwrap();
+        assert_eq!(outputs[0].info.fact, vec![2]);
+
+        store.delete_model(clone).unwrap();
+        assert_eq!(live_graphs(&store), 0);
+    }
+
+    #[test]
+    fn dedup_shares_graphs_unless_disabled() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let graph_of = |store: &ModelStore, model_id| {
+            store
+                .use_model(model_id, |model| Arc::clone(&model.onnx))
+                .unwrap()
+        };
+
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let (second, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        assert!(Arc::ptr_eq(
+            &graph_of(&store, first),
+            &graph_of(&store, second)
+        ));
+        // a failed upload doesn't keep a reference on the shared graph
+        let invalid = ModelOptions {
+            ab_unoptimized_fraction: Some(2.0),
+            ..Default::default()
+        };
+        assert!(store.add_model(&graph, None, invalid).is_err());
+        store.delete_model(first).unwrap();
+        // the other model still has the shared graph
+        assert_eq!(live_graphs(&store), 1);
+        let outputs = store
+            .use_model(second, |model| {
+                model.run_inference(&[] as &[SerializedTensor], &InferenceOptions::default())
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(outputs.0.len(), 1);
+        store.delete_model(second).unwrap();
+        assert_eq!(live_graphs(&store), 0);
+        // the dead entry is pruned by the next upload
+        let other = model(
+            vec![constant("values", &[2.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        store
+            .add_model(&other, None, ModelOptions::default())
+            .unwrap();
+        assert_eq!(store.inner.read().unwrap().onnx_by_hash.len(), 1);
+
+        let store = ModelStore::with_config(ModelStoreConfig {
+            disable_dedup: true,
+            ..Default::default()
+        });
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let (second, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        assert!(!Arc::ptr_eq(
+            &graph_of(&store, first),
+            &graph_of(&store, second)
+        ));
+        assert_eq!(live_graphs(&store), 0);
+    }
+
+    #[test]
+    fn concurrent_add_and_delete() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = Arc::new(ModelStore::with_config(ModelStoreConfig::default()));
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let deleted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (graph, store, ids, deleted) = (
+                    graph.clone(),
+                    Arc::clone(&store),
+                    Arc::clone(&ids),
+                    Arc::clone(&deleted),
+                );
+                std::thread::spawn(move || {
+                    for j in 0..50 {
+                        let (model_id, _) = store
+                            .add_model(&graph, None, ModelOptions::default())
+                            .unwrap();
+                        ids.lock().unwrap().push(model_id);
+                        // every thread tries to delete a model some other thread may be
+                        // deleting as well
+                        let target = {
+                            let ids = ids.lock().unwrap();
+                            ids[(i * 7 + j) % ids.len()]
+                        };
+                        if store.delete_model(target).is_some() {
+                            deleted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        store.self_check().unwrap();
+
+        let ids = ids.lock().unwrap();
+        let remaining = ids
+            .iter()
+            .filter(|model_id| store.delete_model(**model_id).is_some())
+            .count();
+        // each model was deleted exactly once
+        assert_eq!(
+            deleted.load(std::sync::atomic::Ordering::SeqCst) + remaining,
+            ids.len()
+        );
+        store.self_check().unwrap();
+        assert_eq!(live_graphs(&store), 0);
+    }
+
+    #[test]
+    fn visit_every_model_once() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        // more than one batch
+        let mut model_ids: Vec<_> = (0..DESCRIPTION_BATCH + 3)
+            .map(|_| {
+                store
+                    .add_model(&graph, None, ModelOptions::default())
+                    .unwrap()
+                    .0
+            })
+            .collect();
+        let mut visited = vec![];
+        store.for_each_model(|description| visited.push(description.model_id));
+        model_ids.sort();
+        visited.sort();
+        assert_eq!(visited, model_ids);
+
+        let listed: Vec<_> = store
+            .list_models()
+            .into_iter()
+            .map(|description| description.model_id)
+            .collect();
+        assert_eq!(listed, model_ids);
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("relu", FLOAT, &[3])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (model_id, model_hash) = store
+            .add_model(&graph, Some("relu".into()), ModelOptions::default())
+            .unwrap();
+        let metadata = store.export_metadata().unwrap();
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].model_hash, model_hash.as_ref());
+        assert_eq!(
+            metadata[0].inputs,
+            vec![TensorMetadata {
+                node_name: "input".into(),
+                datum_type: Some(ModelDatumType::F32),
+                shape: vec![Some(3)],
+            }]
+        );
+
+        let exported = serde_cbor::to_vec(&metadata).unwrap();
+        // the bytes of the model are not part of the export
+        let fields = match serde_cbor::from_slice(&exported).unwrap() {
+            serde_cbor::Value::Array(models) => match &models[0] {
+                serde_cbor::Value::Map(map) => map.keys().cloned().collect::<Vec<_>>(),
+                _ => panic!("ModelMetadata should serialize to a map"),
+            },
+            _ => panic!("the export should serialize to an array"),
+        };
+        assert_eq!(
+            fields,
+            ["inputs", "outputs", "model_id", "model_hash", "model_name"]
+                .map(|field| serde_cbor::Value::Text(field.into()))
+        );
+        let imported: Vec<ModelMetadata> = serde_cbor::from_slice(&exported).unwrap();
+        assert_eq!(imported, metadata);
+        assert_eq!(
+            store.diff_metadata(&imported).unwrap(),
+            MetadataDiff::default()
+        );
+
+        let mut remote = imported;
+        remote[0].model_name = None;
+        let (added, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        assert_eq!(
+            store.diff_metadata(&remote).unwrap(),
+            MetadataDiff {
+                only_local: vec![added],
+                only_remote: vec![],
+                changed: vec![model_id],
             }
+        );
+    }
+
+    #[test]
+    fn metadata_lists_the_outputs_in_graph_order() {
+        let graph = model(
+            vec![
+                node("Relu", &["input"], &["relu"]),
+                node("Neg", &["input"], &["neg"]),
+                node("Abs", &["input"], &["abs"]),
+            ],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![
+                value_info("relu", FLOAT, &[3]),
+                value_info("neg", FLOAT, &[3]),
+                value_info("abs", FLOAT, &[3]),
+            ],
+        );
+        for optimize in [false, true] {
+            let store = ModelStore::with_config(ModelStoreConfig::default());
+            let options = ModelOptions {
+                optimize,
+                ..Default::default()
+            };
+            store.add_model(&graph, None, options).unwrap();
+            let metadata = store.export_metadata().unwrap();
+            let outputs: Vec<_> = metadata[0]
+                .outputs
+                .iter()
+                .map(|output| output.node_name.as_str())
+                .collect();
+            assert_eq!(outputs, ["relu", "neg", "abs"]);
         }
+    }
 
-        Some(model)
+    #[test]
+    fn validate_without_registering() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("relu", FLOAT, &[3])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_models: Some(1),
+            ..Default::default()
+        });
+        let (model_id, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let before = store.stats();
+        let metadata = store
+            .validate_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        assert_eq!(metadata.model_id, Uuid::nil());
+        assert_eq!(
+            metadata.model_hash,
+            digest::digest(&digest::SHA256, &graph).as_ref()
+        );
+        assert_eq!(metadata.inputs[0].node_name, "input");
+        assert_eq!(metadata.outputs[0].node_name, "relu");
+        assert!(matches!(
+            store.validate_model(b"not a model", None, ModelOptions::default()),
+            Err(ModelStoreError::ModelLoadFailed(_))
+        ));
+
+        // the registered model was not evicted to make room
+        assert_eq!(store.list_models().len(), 1);
+        assert!(store.use_model(model_id, |_| ()).is_some());
+        let delta = store.stats().delta(&before);
+        assert_eq!(delta.models_added, 0);
+        assert_eq!(delta.models_evicted, 0);
+    }
+
+    #[test]
+    fn unique_model_names() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            unique_names: true,
+            max_models: Some(2),
+            ..Default::default()
+        });
+        let add = |name: Option<&str>| {
+            store.add_model(&graph, name.map(str::to_owned), ModelOptions::default())
+        };
+        let (first, _) = add(Some("first")).unwrap();
+        assert_eq!(store.model_id_by_name("first"), Some(first));
+        let err = add(Some("first")).unwrap_err();
+        assert!(matches!(err, ModelStoreError::NameCollision(_)));
+        assert_eq!(err.status_code(), 409);
+        // unnamed models never collide
+        add(None).unwrap();
+        add(None).unwrap();
+
+        // the first model was evicted, its name is free again
+        assert_eq!(store.model_id_by_name("first"), None);
+        let (second, _) = add(Some("first")).unwrap();
+        assert_eq!(store.model_id_by_name("first"), Some(second));
+        store.delete_model(second).unwrap();
+        assert_eq!(store.model_id_by_name("first"), None);
+        add(Some("first")).unwrap();
+
+        // names are not indexed unless they are unique
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        for _ in 0..2 {
+            store
+                .add_model(&graph, Some("first".into()), ModelOptions::default())
+                .unwrap();
+        }
+        assert_eq!(store.model_id_by_name("first"), None);
+    }
+
+    #[test]
+    fn describe_the_tensors() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![symbolic_value_info("input", FLOAT, &["N"])],
+            vec![symbolic_value_info("relu", FLOAT, &["N"])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (_, model_hash) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let description = &store.list_models()[0];
+        assert_eq!(description.hex_hash, hex_hash(&model_hash));
+        let tensor = |node_name: &str| TensorMetadata {
+            node_name: node_name.to_string(),
+            datum_type: Some(ModelDatumType::F32),
+            shape: vec![None],
+        };
+        assert_eq!(description.inputs, [tensor("input")]);
+        assert_eq!(description.outputs, [tensor("relu")]);
+    }
+
+    #[test]
+    fn hashes_in_hex() {
+        assert_eq!(
+            hex_hash(&digest::digest(&digest::SHA256, b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (model_id, model_hash) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let hex = store.get_model_hash(&model_id.to_string()).unwrap();
+        assert_eq!(hex, hex_hash(&model_hash));
+        assert_eq!(store.get_uuid_from_hash(&hex), Some(model_id));
+        assert_eq!(store.get_uuid_from_hash("not hex"), None);
+        assert_eq!(store.get_model_hash(&Uuid::new_v4().to_string()), None);
+        assert_eq!(store.get_model_hash("not an id"), None);
+    }
+
+    #[test]
+    fn drain_before_deleting() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = Arc::new(ModelStore::with_config(ModelStoreConfig::default()));
+        let (model_id, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let long_use = |duration| {
+            let (started, start) = std::sync::mpsc::channel();
+            let done = Arc::new(AtomicBool::new(false));
+            let (store, finished) = (Arc::clone(&store), Arc::clone(&done));
+            let thread = std::thread::spawn(move || {
+                store
+                    .use_model(model_id, |model| {
+                        let available = model.check_available();
+                        started.send(()).unwrap();
+                        std::thread::sleep(duration);
+                        finished.store(true, Ordering::SeqCst);
+                        available
+                    })
+                    .unwrap()
+            });
+            start.recv().unwrap();
+            (thread, done)
+        };
+
+        // the long inference keeps the model registered past the timeout
+        let (inference, _) = long_use(Duration::from_millis(300));
+        let err = store
+            .drain_and_delete(model_id, Duration::from_millis(20))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Contended"));
+        assert_eq!(err.status_code(), 503);
+        inference.join().unwrap().unwrap();
+        assert_eq!(store.list_models()[0].status, ModelStatus::Loaded);
+
+        // the deletion waits for the inference, new requests fail fast
+        let (inference, done) = long_use(Duration::from_millis(300));
+        let drain = {
+            let store = Arc::clone(&store);
+            std::thread::spawn(move || store.drain_and_delete(model_id, Duration::from_secs(60)))
+        };
+        while store.list_models()[0].status != ModelStatus::Draining {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let err = store.try_use_model(model_id, |_| ()).unwrap_err();
+        assert!(err.to_string().starts_with("Draining"));
+        assert_eq!(err.status_code(), 503);
+        assert!(store.fork_model(model_id, None, |_| Ok(())).is_err());
+        // only one deletion drains the model
+        let err = store
+            .drain_and_delete(model_id, Duration::from_secs(60))
+            .unwrap_err();
+        assert!(matches!(err, ModelStoreError::Draining(_)));
+        assert!(!done.load(Ordering::SeqCst));
+        drain.join().unwrap().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+        // the model was not draining yet when the inference started
+        inference.join().unwrap().unwrap();
+        assert!(store.use_model(model_id, |_| ()).is_none());
+        assert!(matches!(
+            store.drain_and_delete(model_id, Duration::ZERO),
+            Err(ModelStoreError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn canonical_exports_share_a_graph() {
+        use prost::Message;
+        use tract_onnx::pb::{ModelProto, StringStringEntryProto};
+
+        let graph = model(
+            vec![
+                node("Relu", &["input"], &["relu"]),
+                node("Neg", &["relu"], &["neg"]),
+                node("Abs", &["input"], &["abs"]),
+            ],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![
+                value_info("neg", FLOAT, &[3]),
+                value_info("abs", FLOAT, &[3]),
+            ],
+        );
+        let mut other = ModelProto::decode(&graph[..]).unwrap();
+        other.producer_name = "another exporter".into();
+        other.doc_string = "exported again".into();
+        other.metadata_props.push(StringStringEntryProto {
+            key: "exported_at".into(),
+            value: "today".into(),
+        });
+        other.graph.as_mut().unwrap().node.reverse();
+        let other = other.encode_to_vec();
+        assert_ne!(graph, other);
+        assert_eq!(canonicalize(&graph).unwrap(), canonicalize(&other).unwrap());
+        let canonical = canonicalize(&graph).unwrap();
+        assert_eq!(canonicalize(&canonical).unwrap(), canonical);
+
+        for (canonicalize_models, distinct_graphs) in [(false, 2), (true, 1)] {
+            let store = ModelStore::with_config(ModelStoreConfig {
+                canonicalize_models,
+                ..Default::default()
+            });
+            let (_, first) = store
+                .add_model(&graph, None, ModelOptions::default())
+                .unwrap();
+            let (model_id, second) = store
+                .add_model(&other, None, ModelOptions::default())
+                .unwrap();
+            assert_eq!(first.as_ref() == second.as_ref(), canonicalize_models);
+            // the hash is that of the bytes loaded, canonical or not
+            let loaded = if canonicalize_models {
+                &canonical
+            } else {
+                &graph
+            };
+            assert_eq!(
+                first.as_ref(),
+                digest::digest(&digest::SHA256, loaded).as_ref()
+            );
+            assert_eq!(store.stats().distinct_graphs, distinct_graphs);
+            let outputs = store
+                .use_model(model_id, |model| {
+                    let input = SerializedTensor {
+                        info: TensorInfo {
+                            fact: vec![3],
+                            datum_type: ModelDatumType::F32,
+                            node_name: None,
+                            layout: None,
+                        },
+                        bytes_data: [-1.0f32, 0.0, 2.0]
+                            .iter()
+                            .flat_map(|value| value.to_le_bytes())
+                            .collect(),
+                    };
+                    model.run_inference(&[input], &InferenceOptions::default())
+                })
+                .unwrap()
+                .unwrap()
+                .0;
+            let names: Vec<_> = outputs
+                .iter()
+                .map(|output| output.info.node_name.as_deref())
+                .collect();
+            assert_eq!(names, [Some("neg"), Some("abs")]);
+        }
+    }
+
+    #[test]
+    fn delete_in_one_batch() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            unique_names: true,
+            ..Default::default()
+        });
+        let (named, _) = store
+            .add_model(&graph, Some("named".into()), ModelOptions::default())
+            .unwrap();
+        let (kept, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let (unnamed, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let before = store.stats();
+        let deleted = store.delete_models(&[named, Uuid::new_v4(), unnamed, named]);
+        let deleted: Vec<_> = deleted
+            .iter()
+            .map(|model| model.as_ref().map(InferenceModel::model_id))
+            .collect();
+        assert_eq!(deleted, [Some(named), None, Some(unnamed), None]);
+        assert_eq!(store.stats().delta(&before).models_deleted, 2);
+        assert_eq!(store.list_models().len(), 1);
+        assert!(store.use_model(kept, |_| ()).is_some());
+        assert_eq!(store.model_id_by_name("named"), None);
+        store.self_check().unwrap();
+    }
+
+    #[test]
+    fn stats_delta_counts_the_adds() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let before = store.stats();
+        for _ in 0..5 {
+            store
+                .add_model(&graph, None, ModelOptions::default())
+                .unwrap();
+        }
+        store.delete_model(first).unwrap();
+        let delta = store.stats().delta(&before);
+        assert_eq!(delta.models_added, 5);
+        assert_eq!(delta.models_deleted, 1);
+        assert_eq!(store.stats().models_loaded, 5);
+        assert_eq!(store.stats().distinct_graphs, 1);
+        assert_eq!(store.stats().dedup_ratio(), 5.0);
+        // an older snapshot is not a negative delta
+        assert_eq!(before.delta(&store.stats()).models_added, 0);
+    }
+
+    #[test]
+    fn stream_the_upload() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (buffered, buffered_hash) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let (mid, end) = graph.split_at(graph.len() / 2);
+        let (streamed, streamed_hash) = store
+            .add_model_from_reader(mid.chain(end), None, ModelOptions::default())
+            .unwrap();
+        assert_ne!(buffered, streamed);
+        assert_eq!(streamed_hash.as_ref(), buffered_hash.as_ref());
+        assert_eq!(store.stats().distinct_graphs, 1);
+        store
+            .use_model(streamed, |model| {
+                model.run_inference(&[] as &[SerializedTensor], &InferenceOptions::default())
+            })
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            store.add_model_from_reader(&b"not a model"[..], None, ModelOptions::default()),
+            Err(ModelStoreError::ModelLoadFailed(_))
+        ));
+
+        // the copy checked for integrity is the streamed model
+        let store = ModelStore::with_config(ModelStoreConfig {
+            integrity_check_interval: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        });
+        let (model_id, model_hash) = store
+            .add_model_from_reader(&graph[..], None, ModelOptions::default())
+            .unwrap();
+        assert_eq!(model_hash.as_ref(), buffered_hash.as_ref());
+        assert_eq!(
+            store.use_model(model_id, InferenceModel::verify_integrity),
+            Some(Some(true))
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingSink(Arc<Mutex<Vec<String>>>);
+
+    impl MetricsSink for RecordingSink {
+        fn incr(&self, counter: &str, labels: &[(&str, &str)]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("incr {} {:?}", counter, labels));
+        }
+
+        fn set_gauge(&self, gauge: &str, value: f64, _labels: &[(&str, &str)]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("gauge {} {}", gauge, value));
+        }
+
+        fn observe(&self, histogram: &str, value: f64, _labels: &[(&str, &str)]) {
+            assert!(value >= 0.0);
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("observe {}", histogram));
+        }
+    }
+
+    #[test]
+    fn push_metrics_to_the_sink() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let sink = RecordingSink::default();
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_models: Some(2),
+            ..Default::default()
+        })
+        .with_metrics_sink(sink.clone());
+        let model_ids: Vec<_> = (0..3)
+            .map(|_| {
+                store
+                    .add_model(&graph, None, ModelOptions::default())
+                    .unwrap()
+                    .0
+            })
+            .collect();
+        store
+            .use_model(model_ids[2], |model| {
+                model.run_inference(&[] as &[SerializedTensor], &InferenceOptions::default())
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *sink.0.lock().unwrap(),
+            [
+                "incr models_added []",
+                "gauge models_loaded 1",
+                "incr dedup_hits []",
+                "incr models_added []",
+                "gauge models_loaded 2",
+                "incr dedup_hits []",
+                r#"incr models_evicted [("reason", "capacity")]"#,
+                "incr models_added []",
+                "gauge models_loaded 2",
+                "incr model_uses []",
+                "observe model_use_seconds",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_reader_limit_at_zero() {
+        for block_contended_readers in [false, true] {
+            let store = ModelStore::with_config(ModelStoreConfig {
+                max_concurrent_readers: Some(0),
+                block_contended_readers,
+                ..Default::default()
+            });
+            assert!(store.admit_reader().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn evict_by_policy() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        for (eviction_policy, evicted) in [(EvictionPolicy::Fifo, 0), (EvictionPolicy::Lru, 1)] {
+            let store = ModelStore::with_config(ModelStoreConfig {
+                max_models: Some(2),
+                eviction_policy,
+                ..Default::default()
+            });
+            let mut model_ids: Vec<_> = (0..2)
+                .map(|_| {
+                    store
+                        .add_model(&graph, None, ModelOptions::default())
+                        .unwrap()
+                        .0
+                })
+                .collect();
+            store.use_model(model_ids[0], |_| ()).unwrap();
+            let (third, _) = store
+                .add_model(&graph, None, ModelOptions::default())
+                .unwrap();
+            assert!(store.use_model(model_ids[evicted], |_| ()).is_none());
+            model_ids.remove(evicted);
+            for model_id in model_ids.into_iter().chain([third]) {
+                assert!(store.use_model(model_id, |_| ()).is_some());
+            }
+            assert_eq!(store.stats().models_evicted, 1);
+            // the survivors still share the graph of the evicted model
+            store.self_check().unwrap();
+        }
+    }
+
+    #[test]
+    fn evict_to_fit_the_memory_budget() {
+        let graphs: Vec<_> = [1.0, 2.0, 3.0]
+            .iter()
+            .map(|value| {
+                model(
+                    vec![constant("values", &[*value])],
+                    vec![],
+                    vec![value_info("values", FLOAT, &[1])],
+                )
+            })
+            .collect();
+        let size = graphs[0].len();
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_store_memory: Some(2 * size),
+            ..Default::default()
+        });
+        let add = |graph: &[u8]| store.add_model(graph, None, ModelOptions::default());
+        let (first, _) = add(&graphs[0]).unwrap();
+        // models sharing a graph count once
+        let (copy, _) = add(&graphs[0]).unwrap();
+        let (second, _) = add(&graphs[1]).unwrap();
+        assert_eq!(store.stats().memory_used, 2 * size);
+        assert_eq!(store.stats().models_evicted, 0);
+
+        // the third graph only fits without the first one
+        let (third, _) = add(&graphs[2]).unwrap();
+        assert!(store.use_model(first, |_| ()).is_none());
+        assert!(store.use_model(copy, |_| ()).is_none());
+        for model_id in [second, third] {
+            assert!(store.use_model(model_id, |_| ()).is_some());
+        }
+        assert_eq!(store.stats().models_evicted, 2);
+        assert_eq!(store.stats().memory_used, 2 * size);
+
+        // evicting the models sharing the graph of the new one frees nothing
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_store_memory: Some(2 * size),
+            ..Default::default()
+        });
+        let optimized = ModelOptions {
+            optimize: true,
+            ..Default::default()
+        };
+        let (first, _) = store.add_model(&graphs[0], None, optimized).unwrap();
+        let (second, _) = store
+            .add_model(&graphs[1], None, ModelOptions::default())
+            .unwrap();
+        let options = ModelOptions {
+            ab_unoptimized_fraction: Some(0.5),
+            ..optimized
+        };
+        let (variant, _) = store.add_model(&graphs[0], None, options).unwrap();
+        assert!(store.use_model(second, |_| ()).is_none());
+        for model_id in [first, variant] {
+            assert!(store.use_model(model_id, |_| ()).is_some());
+        }
+        assert_eq!(store.stats().models_evicted, 1);
+        assert_eq!(store.stats().memory_used, 2 * size);
+        store.self_check().unwrap();
+
+        // a model larger than the budget doesn't evict anything
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_store_memory: Some(size - 1),
+            ..Default::default()
+        });
+        let err = store
+            .add_model(&graphs[0], None, ModelOptions::default())
+            .unwrap_err();
+        assert!(matches!(err, ModelStoreError::OverMemoryBudget { .. }));
+        assert_eq!(err.status_code(), 413);
+        assert!(store.list_models().is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_signatures() {
+        use crate::signature::Ed25519Verifier;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let store = ModelStore::with_config(ModelStoreConfig::default()).with_signature_verifier(
+            Ed25519Verifier::new(vec![key_pair.public_key().as_ref().to_vec()]),
+        );
+
+        let signature = key_pair.sign(&graph);
+        assert!(store
+            .add_signed_model(
+                &graph,
+                Some(signature.as_ref()),
+                None,
+                ModelOptions::default()
+            )
+            .is_ok());
+
+        let forged = key_pair.sign(b"another model");
+        for signature in [Some(forged.as_ref()), None] {
+            let err = store
+                .add_signed_model(&graph, signature, None, ModelOptions::default())
+                .unwrap_err();
+            assert!(err.to_string().starts_with("SignatureInvalid"));
+            assert_eq!(err.status_code(), 403);
+        }
+        assert_eq!(store.stats().models_loaded, 1);
+
+        let signature = key_pair.sign(b"not an onnx model");
+        let err = store
+            .add_signed_model(
+                b"not an onnx model",
+                Some(signature.as_ref()),
+                None,
+                ModelOptions::default(),
+            )
+            .unwrap_err();
+        assert!(matches!(err, ModelStoreError::ModelLoadFailed(_)));
+
+        // the keys trusted by the server come from the environment, keys
+        // that can't be parsed trust nothing
+        let public_key: String = key_pair
+            .public_key()
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        for (keys, accepted) in [(public_key.as_str(), true), ("not hex", false)] {
+            std::env::set_var(TRUSTED_MODEL_KEYS, keys);
+            let store = ModelStore::new();
+            std::env::remove_var(TRUSTED_MODEL_KEYS);
+            let unsigned = store.add_model(&graph, None, ModelOptions::default());
+            assert_eq!(unsigned.unwrap_err().status_code(), 403);
+            let signed = store.add_signed_model(
+                &graph,
+                Some(key_pair.sign(&graph).as_ref()),
+                None,
+                ModelOptions::default(),
+            );
+            assert_eq!(signed.is_ok(), accepted);
+        }
+    }
+
+    #[test]
+    fn snapshot_reads_do_not_block_writers() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let snapshot = store.snapshot();
+        assert!(Arc::ptr_eq(&snapshot, &store.snapshot()));
+
+        // uploads go through while the snapshot is being read
+        let mut added = None;
+        for model in &snapshot.models {
+            assert_eq!(model.model_id, first);
+            added = Some(
+                std::thread::scope(|scope| {
+                    scope
+                        .spawn(|| store.add_model(&graph, None, ModelOptions::default()))
+                        .join()
+                })
+                .unwrap()
+                .unwrap()
+                .0,
+            );
+        }
+        assert_eq!(snapshot.models.len(), 1);
+
+        let mut expected = vec![first, added.unwrap()];
+        expected.sort();
+        let refreshed = store.snapshot();
+        assert_eq!(
+            refreshed
+                .models
+                .iter()
+                .map(|model| model.model_id)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn concurrent_uploads_share_one_graph() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[4])],
+            vec![value_info("relu", FLOAT, &[4])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    store
+                        .add_model(&graph, None, ModelOptions::default())
+                        .unwrap()
+                });
+            }
+        });
+        assert_eq!(store.stats().models_loaded, 4);
+        // the graphs loaded by the uploads racing the first one were dropped
+        let graphs: HashSet<_> = store
+            .inner
+            .read()
+            .unwrap()
+            .models_by_id
+            .values()
+            .map(|model| Arc::as_ptr(&model.onnx))
+            .collect();
+        assert_eq!(graphs.len(), 1);
+        store.self_check().unwrap();
+    }
+
+    #[test]
+    fn status_follows_the_inferences() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[4])],
+            vec![value_info("relu", FLOAT, &[4])],
+        );
+        // fails inside tract when the index is out of bounds
+        let broken = model(
+            vec![node("Gather", &["data", "indices"], &["gathered"])],
+            vec![
+                value_info("data", FLOAT, &[1]),
+                value_info("indices", INT64, &[1]),
+            ],
+            vec![value_info("gathered", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            circuit_breaker_threshold: Some(1),
+            circuit_breaker_cooldown: Duration::from_secs(3600),
+            ..Default::default()
+        });
+        let (model_id, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let status = |model_id| store.use_model(model_id, InferenceModel::status).unwrap();
+        assert_eq!(status(model_id), ModelStatus::Loaded);
+
+        let infer = |model_id, inputs: &[SerializedTensor]| {
+            store
+                .use_model(model_id, |model| {
+                    model
+                        .run_inference(inputs, &InferenceOptions::default())
+                        .is_ok()
+                })
+                .unwrap()
+        };
+        let inputs = store
+            .use_model(model_id, |model| model.zero_inputs().unwrap())
+            .unwrap();
+        // inputs of another shape are the client's failure, not the model's
+        let mut wrong = inputs.clone();
+        wrong[0].info.fact = vec![2];
+        wrong[0].bytes_data.truncate(8);
+        assert!(!infer(model_id, &wrong));
+        assert_eq!(status(model_id), ModelStatus::Loaded);
+        assert!(infer(model_id, &inputs));
+        assert_eq!(status(model_id), ModelStatus::WarmedUp);
+
+        let (broken_id, _) = store
+            .add_model(&broken, None, ModelOptions::default())
+            .unwrap();
+        let mut out_of_bounds = store
+            .use_model(broken_id, |model| model.zero_inputs().unwrap())
+            .unwrap();
+        out_of_bounds[1].bytes_data = 5i64.to_le_bytes().to_vec();
+        assert!(!infer(broken_id, &out_of_bounds));
+        assert_eq!(status(broken_id), ModelStatus::Degraded);
+    }
+
+    #[test]
+    fn sweep_expired_models() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let add = |store: &ModelStore| {
+            store
+                .add_model(&graph, None, ModelOptions::default())
+                .unwrap()
+        };
+        let store = ModelStore::with_config(ModelStoreConfig {
+            model_ttl: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        });
+        add(&store);
+        assert_eq!(store.sweep_expired(), 0);
+
+        let store = ModelStore::with_config(ModelStoreConfig {
+            model_ttl: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        add(&store);
+        add(&store);
+        assert_eq!(store.sweep_expired(), 2);
+        assert_eq!(store.stats().models_loaded, 0);
+        assert_eq!(store.stats().models_evicted, 2);
+        assert_eq!(live_graphs(&store), 0);
+        store.self_check().unwrap();
+    }
+
+    #[test]
+    fn prepare_before_registering() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_models: Some(1),
+            ..Default::default()
+        });
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let err = store
+            .add_prepared_model(&graph, None, None, ModelOptions::default(), |model| {
+                // not registered yet
+                assert!(store.use_model(model.model_id(), |_| ()).is_none());
+                Err(anyhow!("Warm-up failed"))
+            })
+            .unwrap_err();
+        assert!(matches!(err, ModelStoreError::ModelLoadFailed(_)));
+        // the model that failed made no room
+        assert!(store.use_model(first, |_| ()).is_some());
+        assert_eq!(store.stats().models_evicted, 0);
+
+        let (second, _) = store
+            .add_prepared_model(&graph, None, None, ModelOptions::default(), |model| {
+                model.warm_up(&[]).map(|_| ())
+            })
+            .unwrap();
+        assert_eq!(
+            store.use_model(second, InferenceModel::status),
+            Some(ModelStatus::WarmedUp)
+        );
+    }
+
+    #[test]
+    fn notify_the_removals() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_models: Some(2),
+            ..Default::default()
+        });
+        let removed = Arc::new(Mutex::new(vec![]));
+        {
+            let removed = Arc::clone(&removed);
+            store.on_model_removed(move |model_id| removed.lock().unwrap().push(model_id));
+        }
+        let add = || {
+            store
+                .add_model(&graph, None, ModelOptions::default())
+                .unwrap()
+                .0
+        };
+        let (first, second) = (add(), add());
+        assert!(removed.lock().unwrap().is_empty());
+        // evicted, replaced, deleted
+        let third = add();
+        store
+            .replace_model(second, &graph, None, ModelOptions::default())
+            .unwrap();
+        store.delete_model(third).unwrap();
+        assert_eq!(*removed.lock().unwrap(), [first, second, third]);
+        // nothing to remove
+        assert!(store.delete_model(third).is_none());
+        assert_eq!(removed.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn replace_in_place() {
+        let constant_model = |value| {
+            model(
+                vec![constant("values", &[value])],
+                vec![],
+                vec![value_info("values", FLOAT, &[1])],
+            )
+        };
+        let output = |store: &ModelStore, model_id| {
+            store
+                .use_model(model_id, |model| {
+                    model
+                        .run_inference(&[] as &[SerializedTensor], &InferenceOptions::default())
+                        .unwrap()
+                        .0[0]
+                        .bytes_data
+                        .clone()
+                })
+                .unwrap()
+        };
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (model_id, _) = store
+            .add_model(
+                &constant_model(1.0),
+                Some("constant".into()),
+                ModelOptions::default(),
+            )
+            .unwrap();
+        let version = store.model_version(model_id).unwrap();
+
+        let model_hash = store
+            .replace_model(
+                model_id,
+                &constant_model(2.0),
+                None,
+                ModelOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(output(&store, model_id), 2.0f32.to_le_bytes());
+        let description = &store.list_models()[0];
+        assert_eq!(description.model_hash, model_hash.as_ref());
+        assert_eq!(description.model_name.as_deref(), Some("constant"));
+        assert!(store.model_version(model_id).unwrap() > version);
+        // the graph of the old bytes went away with the old model
+        assert_eq!(live_graphs(&store), 1);
+
+        let err = store
+            .replace_model(model_id, b"not a model", None, ModelOptions::default())
+            .unwrap_err();
+        assert!(matches!(err, ModelStoreError::ModelLoadFailed(_)));
+        assert_eq!(output(&store, model_id), 2.0f32.to_le_bytes());
+
+        let err = store
+            .replace_model(
+                Uuid::new_v4(),
+                &constant_model(3.0),
+                None,
+                ModelOptions::default(),
+            )
+            .unwrap_err();
+        assert_eq!(err.status_code(), 404);
+        assert_eq!(store.stats().models_loaded, 1);
+        store.self_check().unwrap();
+
+        // the new bytes are held to the memory budget, in place of the old ones
+        let size = constant_model(1.0).len();
+        let store = ModelStore::with_config(ModelStoreConfig {
+            max_store_memory: Some(2 * size),
+            ..Default::default()
+        });
+        let add = |value| {
+            store
+                .add_model(&constant_model(value), None, ModelOptions::default())
+                .unwrap()
+                .0
+        };
+        let (first, second) = (add(1.0), add(2.0));
+        store
+            .replace_model(first, &constant_model(3.0), None, ModelOptions::default())
+            .unwrap();
+        assert_eq!(store.stats().models_evicted, 0);
+        let larger = model(
+            vec![constant("values", &[4.0, 5.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[2])],
+        );
+        assert!(larger.len() > size && larger.len() <= 2 * size);
+        store
+            .replace_model(first, &larger, None, ModelOptions::default())
+            .unwrap();
+        assert!(store.use_model(second, |_| ()).is_none());
+        assert_eq!(store.stats().models_evicted, 1);
+        assert_eq!(store.stats().memory_used, larger.len());
+
+        let too_large = model(
+            vec![constant("values", &[0.0; 64])],
+            vec![],
+            vec![value_info("values", FLOAT, &[64])],
+        );
+        let err = store
+            .replace_model(first, &too_large, None, ModelOptions::default())
+            .unwrap_err();
+        assert!(matches!(err, ModelStoreError::OverMemoryBudget { .. }));
+        assert!(store.use_model(first, |_| ()).is_some());
+        store.self_check().unwrap();
+    }
+
+    #[test]
+    fn dedup_by_optimization() {
+        let graph = model(
+            vec![node("Relu", &["input"], &["relu"])],
+            vec![value_info("input", FLOAT, &[4])],
+            vec![value_info("relu", FLOAT, &[4])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let add = |optimize| {
+            store
+                .add_model(
+                    &graph,
+                    None,
+                    ModelOptions {
+                        optimize,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .0
+        };
+        let graph_of = |model_id| {
+            store
+                .use_model(model_id, |model| Arc::clone(&model.onnx))
+                .unwrap()
+        };
+        let plain = add(false);
+        let optimized = add(true);
+        let other_optimized = add(true);
+        assert!(!Arc::ptr_eq(&graph_of(plain), &graph_of(optimized)));
+        assert!(Arc::ptr_eq(
+            &graph_of(optimized),
+            &graph_of(other_optimized)
+        ));
+        assert_eq!(live_graphs(&store), 2);
+
+        // a clone shares the graph, and so keeps its optimization
+        let (clone, _) = store
+            .clone_model(
+                plain,
+                None,
+                Some(ModelOptions {
+                    optimize: true,
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+        assert!(Arc::ptr_eq(&graph_of(plain), &graph_of(clone)));
+        assert!(!store
+            .use_model(clone, InferenceModel::is_optimized)
+            .unwrap());
+        store.self_check().unwrap();
+    }
+
+    #[test]
+    fn versions_grow_with_registrations() {
+        let graph = model(
+            vec![constant("values", &[1.0])],
+            vec![],
+            vec![value_info("values", FLOAT, &[1])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (first, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let (second, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let (clone, _) = store.clone_model(first, None, None).unwrap();
+        let versions: Vec<_> = [first, second, clone]
+            .into_iter()
+            .map(|model_id| store.model_version(model_id).unwrap())
+            .collect();
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // uses do not change the version
+        store.use_model(first, |_| ()).unwrap();
+        assert_eq!(store.model_version(first), Some(versions[0]));
+        store.delete_model(first).unwrap();
+        assert_eq!(store.model_version(first), None);
+    }
+
+    #[test]
+    fn fork_with_a_stripped_head() {
+        let graph = model(
+            vec![
+                node("Relu", &["input"], &["relu"]),
+                node("Neg", &["relu"], &["neg"]),
+            ],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("neg", FLOAT, &[3])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (original, original_hash) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        // output the input of the last node instead of its result
+        let strip_neg = |graph: &mut TypedModel| {
+            let neg = graph.output_outlets()?[0].node;
+            let relu = graph.node(neg).inputs[0];
+            graph.set_output_outlets(&[relu])
+        };
+        let (fork, fork_hash) = store.fork_model(original, None, strip_neg).unwrap();
+        assert_ne!(fork_hash.as_ref(), original_hash.as_ref());
+        // the same transform gives the same graph, which is shared
+        let (again, again_hash) = store.fork_model(original, None, strip_neg).unwrap();
+        assert_eq!(again_hash.as_ref(), fork_hash.as_ref());
+        store.self_check().unwrap();
+
+        let input = [SerializedTensor {
+            info: TensorInfo {
+                fact: vec![3],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [-1.0f32, 0.5, 2.0]
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        }];
+        let outputs = store
+            .use_model(fork, |model| {
+                model
+                    .run_inference(&input, &InferenceOptions::default())
+                    .unwrap()
+                    .0
+            })
+            .unwrap();
+        let values: Vec<f32> = outputs[0]
+            .bytes_data
+            .chunks(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, vec![0.0, 0.5, 2.0]);
+
+        for model_id in [original, fork, again] {
+            store.delete_model(model_id).unwrap();
+        }
+        assert_eq!(live_graphs(&store), 0);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn mobilenet_is_deterministic() {
+        let store = ModelStore::new();
+        let (model_id, _) = store
+            .add_model(
+                MOBILENET,
+                None,
+                ModelOptions {
+                    optimize: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(store.check_determinism(model_id, 3).unwrap());
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn trace_every_node() {
+        let graph = model(
+            vec![
+                node("Relu", &["input"], &["relu"]),
+                node("Neg", &["relu"], &["neg"]),
+            ],
+            vec![value_info("input", FLOAT, &[3])],
+            vec![value_info("neg", FLOAT, &[3])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (model_id, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let input = [SerializedTensor {
+            info: TensorInfo {
+                fact: vec![3],
+                datum_type: ModelDatumType::F32,
+                node_name: None,
+                layout: None,
+            },
+            bytes_data: [-1.0f32, 0.5, 2.0]
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        }];
+        let num_nodes = store
+            .use_model(model_id, |model| model.onnx.model().nodes().len())
+            .unwrap();
+
+        let (outputs, trace) = store
+            .run_with_trace(model_id, &input, TraceDetail::Digests)
+            .unwrap();
+        assert_eq!(trace.len(), num_nodes);
+        assert!(trace
+            .iter()
+            .flat_map(|node| &node.outputs)
+            .all(|output| output.tensor.is_none()));
+        let (reference, _) = store
+            .use_model(model_id, |model| {
+                model.run_inference(&input, &InferenceOptions::default())
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(outputs[0].bytes_data, reference[0].bytes_data);
+
+        let (_, full_trace) = store
+            .run_with_trace(model_id, &input, TraceDetail::Tensors)
+            .unwrap();
+        let last = &full_trace.last().unwrap().outputs[0];
+        assert_eq!(last.shape, vec![3]);
+        assert_eq!(
+            last.tensor.as_ref().unwrap().as_slice::<f32>().unwrap(),
+            &[0.0, -0.5, -2.0]
+        );
+        // the digests do not depend on the detail of the trace
+        assert!(trace
+            .iter()
+            .zip(&full_trace)
+            .all(|(a, b)| a.outputs[0].digest.as_ref() == b.outputs[0].digest.as_ref()));
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn profile_every_node() {
+        let nodes: Vec<_> = (0..8)
+            .map(|i| match i {
+                0 => node("Relu", &["input"], &["relu_0"]),
+                _ => node(
+                    "Relu",
+                    &[&format!("relu_{}", i - 1)],
+                    &[&format!("relu_{i}")],
+                ),
+            })
+            .collect();
+        let graph = model(
+            nodes,
+            vec![value_info("input", FLOAT, &[1024])],
+            vec![value_info("relu_7", FLOAT, &[1024])],
+        );
+        let store = ModelStore::with_config(ModelStoreConfig::default());
+        let (model_id, _) = store
+            .add_model(&graph, None, ModelOptions::default())
+            .unwrap();
+        let inputs = store
+            .use_model(model_id, |model| model.zero_inputs().unwrap())
+            .unwrap();
+        let num_nodes = store
+            .use_model(model_id, |model| model.onnx.model().nodes().len())
+            .unwrap();
+
+        let (outputs, profile, total) = store.run_with_profile(model_id, &inputs).unwrap();
+        assert_eq!(outputs[0].info.fact, vec![1024]);
+        assert_eq!(profile.len(), num_nodes);
+        assert!(profile
+            .windows(2)
+            .all(|pair| pair[0].elapsed >= pair[1].elapsed));
+        // one timing per node of the graph, each within the inference
+        let mut node_ids: Vec<_> = profile.iter().map(|node| node.node_id).collect();
+        node_ids.sort_unstable();
+        assert_eq!(node_ids, (0..num_nodes).collect::<Vec<_>>());
+        assert!(profile.iter().all(|node| node.elapsed <= total));
+        let nodes_time: Duration = profile.iter().map(|node| node.elapsed).sum();
+        assert!(nodes_time <= total);
     }
 }
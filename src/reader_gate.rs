@@ -0,0 +1,99 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{bail, Result};
+use std::sync::{Condvar, Mutex};
+
+/// Bounds the number of threads reading the store at the same time.
+///
+/// Readers past the limit either wait for a permit or are turned away with a
+/// `Contended` error, depending on `block`.
+pub(crate) struct ReaderGate {
+    max_readers: usize,
+    block: bool,
+    readers: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Permit to read the store, given back when dropped.
+pub(crate) struct ReaderPermit<'a> {
+    gate: &'a ReaderGate,
+}
+
+impl ReaderGate {
+    pub fn new(max_readers: usize, block: bool) -> Self {
+        ReaderGate {
+            max_readers,
+            block,
+            readers: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn enter(&self) -> Result<ReaderPermit<'_>> {
+        let mut readers = self.readers.lock().unwrap();
+        while *readers >= self.max_readers {
+            if !self.block {
+                bail!(
+                    "Contended: {} requests are already being served, retry later",
+                    self.max_readers
+                );
+            }
+            readers = self.released.wait(readers).unwrap();
+        }
+        *readers += 1;
+        Ok(ReaderPermit { gate: self })
+    }
+}
+
+impl Drop for ReaderPermit<'_> {
+    fn drop(&mut self) {
+        *self.gate.readers.lock().unwrap() -= 1;
+        self.gate.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reader_past_the_limit_is_gated() {
+        let gate = ReaderGate::new(2, false);
+        let first = gate.enter().unwrap();
+        let _second = gate.enter().unwrap();
+        let err = gate.enter().err().unwrap();
+        assert!(err.to_string().starts_with("Contended"));
+        drop(first);
+        assert!(gate.enter().is_ok());
+
+        let gate = ReaderGate::new(1, true);
+        let (entered_tx, entered_rx) = mpsc::channel();
+        thread::scope(|scope| {
+            let first = gate.enter().unwrap();
+            let gate = &gate;
+            scope.spawn(move || {
+                let _permit = gate.enter().unwrap();
+                entered_tx.send(()).unwrap();
+            });
+            // the second reader waits for the first one
+            assert!(entered_rx.recv_timeout(Duration::from_millis(50)).is_err());
+            drop(first);
+            entered_rx.recv().unwrap();
+        });
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, bail, Result};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::str::FromStr;
+
+/// Checks the detached signature of a model before the store parses it.
+pub trait ModelSignatureVerifier: Send + Sync {
+    fn verify(&self, model_bytes: &[u8], signature: &[u8]) -> Result<()>;
+}
+
+/// Accepts the models signed with Ed25519 by one of the trusted keys.
+pub struct Ed25519Verifier {
+    trusted_keys: Vec<Vec<u8>>,
+}
+
+impl Ed25519Verifier {
+    /// `trusted_keys` are raw 32 byte public keys.
+    pub fn new(trusted_keys: Vec<Vec<u8>>) -> Self {
+        Ed25519Verifier { trusted_keys }
+    }
+}
+
+/// Parses the trusted keys as hex encoded raw public keys, separated by
+/// commas.
+impl FromStr for Ed25519Verifier {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let trusted_keys = s
+            .split(',')
+            .map(|key| {
+                let key = ring::test::from_hex(key.trim())
+                    .map_err(|_| anyhow!("{:?} is not hex encoded", key))?;
+                if key.len() != 32 {
+                    bail!("an Ed25519 public key is 32 bytes, not {}", key.len());
+                }
+                Ok(key)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Ed25519Verifier::new(trusted_keys))
+    }
+}
+
+impl ModelSignatureVerifier for Ed25519Verifier {
+    fn verify(&self, model_bytes: &[u8], signature: &[u8]) -> Result<()> {
+        let trusted = self.trusted_keys.iter().any(|key| {
+            UnparsedPublicKey::new(&ED25519, key)
+                .verify(model_bytes, signature)
+                .is_ok()
+        });
+        if !trusted {
+            bail!("SignatureInvalid: the model is not signed by a trusted key");
+        }
+        Ok(())
+    }
+}
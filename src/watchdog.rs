@@ -0,0 +1,162 @@
+// Copyright 2022 Mithril Security. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::metrics::MetricsSink;
+use crate::model_store::ModelStore;
+use log::warn;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+struct InFlightInference {
+    model_id: Uuid,
+    started: Instant,
+    timed_out: Arc<AtomicBool>,
+}
+
+/// Keeps track of the in-flight inferences and flags the ones running for
+/// longer than `max_duration`.
+///
+/// tract inferences cannot be preempted: a flagged inference keeps running
+/// until it finishes, its result is then discarded and the client gets an
+/// error instead.
+pub(crate) struct InferenceWatchdog {
+    max_duration: Duration,
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlightInference>>,
+    timed_out_count: AtomicU64,
+}
+
+/// Registration of an inference in the watchdog, removed when dropped.
+pub(crate) struct InferenceGuard<'a> {
+    watchdog: &'a InferenceWatchdog,
+    id: u64,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl InferenceWatchdog {
+    pub fn new(max_duration: Duration) -> Self {
+        InferenceWatchdog {
+            max_duration,
+            next_id: AtomicU64::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+            timed_out_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Spawns the thread checking the in-flight inferences every `period`,
+    /// the timeouts are reported to the metrics sink of `model_store`.
+    pub fn spawn(self: &Arc<Self>, period: Duration, model_store: Arc<ModelStore>) {
+        let watchdog = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(period);
+            if watchdog.check() > 0 {
+                model_store.emit(|sink| watchdog.report(sink));
+            }
+        });
+    }
+
+    pub fn start(&self, model_id: Uuid) -> InferenceGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        self.in_flight.lock().unwrap().insert(
+            id,
+            InFlightInference {
+                model_id,
+                started: Instant::now(),
+                timed_out: Arc::clone(&timed_out),
+            },
+        );
+        InferenceGuard {
+            watchdog: self,
+            id,
+            timed_out,
+        }
+    }
+
+    /// Flags the inferences that went past the deadline, returns how many
+    /// were newly flagged.
+    pub fn check(&self) -> usize {
+        let in_flight = self.in_flight.lock().unwrap();
+        let mut flagged = 0;
+        for inference in in_flight.values() {
+            let elapsed = inference.started.elapsed();
+            if elapsed > self.max_duration && !inference.timed_out.swap(true, Ordering::Relaxed) {
+                let total = self.timed_out_count.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Inference on model {} is still running after {:?} ({} inferences timed out so far)",
+                    inference.model_id, elapsed, total
+                );
+                flagged += 1;
+            }
+        }
+        flagged
+    }
+
+    /// Pushes the number of inferences that timed out so far to `sink`.
+    pub fn report(&self, sink: &dyn MetricsSink) {
+        let total = self.timed_out_count.load(Ordering::Relaxed);
+        sink.set_gauge("inferences_timed_out", total as f64, &[]);
+    }
+}
+
+impl InferenceGuard<'_> {
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InferenceGuard<'_> {
+    fn drop(&mut self) {
+        self.watchdog.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_inference_is_flagged() {
+        let watchdog = InferenceWatchdog::new(Duration::from_millis(10));
+        let fast = watchdog.start(Uuid::new_v4());
+        assert_eq!(watchdog.check(), 0);
+        drop(fast);
+
+        let slow = watchdog.start(Uuid::new_v4());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(watchdog.check(), 1);
+        // already flagged inferences are only counted once
+        assert_eq!(watchdog.check(), 0);
+        assert!(slow.timed_out());
+        assert_eq!(watchdog.timed_out_count.load(Ordering::Relaxed), 1);
+
+        struct GaugeSink(Mutex<Option<f64>>);
+        impl MetricsSink for GaugeSink {
+            fn set_gauge(&self, gauge: &str, value: f64, _labels: &[(&str, &str)]) {
+                assert_eq!(gauge, "inferences_timed_out");
+                *self.0.lock().unwrap() = Some(value);
+            }
+        }
+        let sink = GaugeSink(Mutex::new(None));
+        watchdog.report(&sink);
+        assert_eq!(*sink.0.lock().unwrap(), Some(1.0));
+
+        drop(slow);
+        assert!(watchdog.in_flight.lock().unwrap().is_empty());
+    }
+}